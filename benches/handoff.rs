@@ -0,0 +1,193 @@
+// Worst-case time the player thread needs for a bucket while the network thread hands received
+// audio over and the capture thread, the control socket and the status display use the playback
+// state. Once with the mixer behind a mutex everybody locks, with the capture thread keeping the
+// duplex state locked while encoding and sending, and once with the mixer owned by the player.
+// The hand overs of the network thread are timed as well. Run with `cargo bench --bench handoff`.
+extern crate walkie_talkie_pi;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use walkie_talkie_pi::audio::{self, AudioBuffer, AudioData, Bandwidth, Burst, BurstMarker, Mixer, Priority, SampleFormat, StreamFormat};
+use walkie_talkie_pi::capture::WireEncoder;
+use walkie_talkie_pi::echo::{Duplex, DuplexMode};
+use walkie_talkie_pi::packet_layer::{self, PayloadPacket, SendablePackets};
+
+// few streams, so the cost of mixing does not hide the waits
+const STREAMS: u16 = 4;
+const BUCKETS: u64 = 5000;
+const WRITE_BUCKET_LEN: u64 = 1400;
+const READ_BUCKET_LEN: u32 = 4000;
+
+fn format() -> StreamFormat {
+    StreamFormat { sample_rate: 44100, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full }
+}
+
+fn bucket(n: u64) -> AudioData {
+    let client_id = (n % STREAMS as u64) as u16 + 1;
+    let pos = 1 + n / STREAMS as u64 * WRITE_BUCKET_LEN;
+    let marker = if pos == 1 { BurstMarker::Start } else { BurstMarker::Continue };
+    AudioData { client_id: client_id, pos: pos, format: format(), burst: Burst { id: 1, marker: marker }, priority: Priority::Normal, destination: None, data: vec![1000; WRITE_BUCKET_LEN as usize] }
+}
+
+fn new_buffer() -> AudioBuffer {
+    AudioBuffer::new(1400 * 100, 20000, 500, format())
+}
+
+fn new_duplex() -> Arc<Mutex<Duplex>> {
+    Arc::new(Mutex::new(Duplex::new(DuplexMode::Full, 1400 * 100, 500, 44100)))
+}
+
+// what the capture thread does with a bucket besides the duplex bookkeeping
+fn encode_and_send(encoder: &mut WireEncoder, n: u64) {
+    let mut data = bucket(n);
+    data.client_id = 0;
+    if let Ok(data) = encoder.encode(data, Burst { id: 1, marker: BurstMarker::Continue }) {
+        let _ = packet_layer::encode(&SendablePackets::PayloadPacket(PayloadPacket::new(data, 1, n as u8)));
+    }
+}
+
+// the player handles a bucket about once per device period, every call is timed
+fn spawn_player<F>(stop: Arc<AtomicBool>, mut play: F) -> thread::JoinHandle<Vec<Duration>>
+    where F: FnMut() + Send + 'static
+{
+    thread::spawn(move || {
+        let mut times = Vec::new();
+        while !stop.load(Ordering::SeqCst) {
+            let start = Instant::now();
+            play();
+            times.push(start.elapsed());
+            thread::sleep(Duration::from_millis(1));
+        }
+        times
+    })
+}
+
+// capture, control socket and status display, all of them far more often than in practice
+fn spawn_load<F>(stop: Arc<AtomicBool>, mut work: F) -> thread::JoinHandle<()>
+    where F: FnMut(u64) + Send + 'static
+{
+    thread::spawn(move || {
+        let mut n = 0;
+        while !stop.load(Ordering::SeqCst) {
+            work(n);
+            n += 1;
+            thread::sleep(Duration::from_micros(100));
+        }
+    })
+}
+
+// times every hand over, the receiver gets a bucket every 100 us
+fn receive<F>(mut hand_over: F) -> Vec<Duration>
+    where F: FnMut(AudioData)
+{
+    let mut times = Vec::with_capacity(BUCKETS as usize);
+    for n in 0..BUCKETS {
+        let data = bucket(n);
+        let start = Instant::now();
+        hand_over(data);
+        times.push(start.elapsed());
+        thread::sleep(Duration::from_micros(100));
+    }
+    times
+}
+
+fn report(name: &str, mut times: Vec<Duration>) {
+    times.sort();
+    let micros = |d: Duration| d.as_secs() * 1000000 + d.subsec_nanos() as u64 / 1000;
+    let at = |q: f64| micros(times[((times.len() - 1) as f64 * q) as usize]);
+    println!("{:<16} median {:>6} us   p99 {:>6} us   p99.9 {:>6} us   max {:>6} us", name, at(0.5), at(0.99), at(0.999), at(1.0));
+}
+
+fn stop_all(stop: &AtomicBool, load: Vec<thread::JoinHandle<()>>, player: thread::JoinHandle<Vec<Duration>>) -> Vec<Duration> {
+    stop.store(true, Ordering::SeqCst);
+    for thread in load {
+        thread.join().unwrap();
+    }
+    player.join().unwrap()
+}
+
+fn shared_mixer() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let buffer = Arc::new(Mutex::new(new_buffer()));
+    let duplex = new_duplex();
+    let (player_buffer, player_duplex) = (buffer.clone(), duplex.clone());
+    let player = spawn_player(stop.clone(), move || {
+        let data = {
+            let mut buffer = player_buffer.lock().unwrap();
+            buffer.set_muted(player_duplex.lock().unwrap().playback_muted());
+            buffer.get_next(READ_BUCKET_LEN).unwrap_or_else(|| vec![0; READ_BUCKET_LEN as usize])
+        };
+        player_duplex.lock().unwrap().note_played(&data, 0);
+    });
+    let (capture_buffer, capture_duplex) = (buffer.clone(), duplex.clone());
+    let mut encoder = WireEncoder::new(format(), Bandwidth::Wide, Priority::Normal);
+    let capture = spawn_load(stop.clone(), move |n| {
+        let mut duplex = capture_duplex.lock().unwrap();
+        duplex.note_captured(&[1000; WRITE_BUCKET_LEN as usize]);
+        capture_buffer.lock().unwrap().check_busy();
+        encode_and_send(&mut encoder, n);
+    });
+    let control_buffer = buffer.clone();
+    let control = spawn_load(stop.clone(), move |n| {
+        let mut buffer = control_buffer.lock().unwrap();
+        buffer.set_volume(50 + n as u32 % 50);
+        let _ = (buffer.talkers(), buffer.user_muted(), buffer.volume());
+    });
+    let (display_buffer, display_duplex) = (buffer.clone(), duplex.clone());
+    let display = spawn_load(stop.clone(), move |_| {
+        let _ = {
+            let duplex = display_duplex.lock().unwrap();
+            (duplex.input_level(), duplex.output_level())
+        };
+        let buffer = display_buffer.lock().unwrap();
+        let _ = (buffer.talkers(), buffer.peer_stats());
+    });
+    let times = receive(|data| {
+        let _ = buffer.lock().unwrap().store_data(data);
+    });
+    report("mutex receive", times);
+    report("mutex player", stop_all(&stop, vec![capture, control, display], player));
+}
+
+fn owned_mixer() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let duplex = new_duplex();
+    let (mut queue, queued) = audio::play_queue();
+    let mut mixer = Mixer::new(new_buffer(), queued, READ_BUCKET_LEN).with_duplex(duplex.clone());
+    let playback = mixer.control();
+    let player_duplex = duplex.clone();
+    let player = spawn_player(stop.clone(), move || {
+        let data = mixer.next_bucket();
+        player_duplex.lock().unwrap().note_played(&data, 0);
+    });
+    let (capture_playback, capture_duplex) = (playback.clone(), duplex.clone());
+    let mut encoder = WireEncoder::new(format(), Bandwidth::Wide, Priority::Normal);
+    let capture = spawn_load(stop.clone(), move |n| {
+        capture_duplex.lock().unwrap().note_captured(&[1000; WRITE_BUCKET_LEN as usize]);
+        capture_playback.check_busy();
+        encode_and_send(&mut encoder, n);
+    });
+    let control_playback = playback.clone();
+    let control = spawn_load(stop.clone(), move |n| {
+        control_playback.set_volume(50 + n as u32 % 50);
+        let _ = (control_playback.status().talkers, control_playback.user_muted(), control_playback.volume());
+    });
+    let (display_playback, display_duplex) = (playback.clone(), duplex.clone());
+    let display = spawn_load(stop.clone(), move |_| {
+        let _ = {
+            let duplex = display_duplex.lock().unwrap();
+            (duplex.input_level(), duplex.output_level())
+        };
+        let _ = display_playback.status();
+    });
+    let times = receive(|data| audio::queue_for_playback(&mut queue, data));
+    report("owned receive", times);
+    report("owned player", stop_all(&stop, vec![capture, control, display], player));
+}
+
+fn main() {
+    shared_mixer();
+    owned_mixer();
+}
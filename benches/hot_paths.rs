@@ -0,0 +1,159 @@
+// CPU cost of the stages every bucket of audio passes through. Run with
+// `cargo bench --bench hot_paths`; to compare releases, save a baseline with
+// `-- --save-baseline NAME` and compare against it later with `-- --baseline NAME`.
+extern crate criterion;
+extern crate walkie_talkie_pi;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use walkie_talkie_pi::audio::{AudioBuffer, AudioData, Bandwidth, Burst, BurstMarker, Priority, RingBuffer, SampleFormat, StreamFormat};
+use walkie_talkie_pi::packet_layer::{self, AdvertisementPacket, MemorySocket, PayloadPacket, SendablePackets};
+
+// the defaults of the buffer settings
+const RING_BUFFER_LEN: u32 = 1400 * 100;
+const SPARE_LEN: u64 = 20000;
+const IDLE_THRESHOLD: u32 = 500;
+const WRITE_BUCKET_LEN: u64 = 1400;
+const READ_BUCKET_LEN: u32 = 4000;
+
+fn format() -> StreamFormat {
+    StreamFormat { sample_rate: 44100, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full }
+}
+
+fn samples(len: u64) -> Vec<i16> {
+    (0..len).map(|i| ((i * 97) % 2000) as i16 - 1000).collect()
+}
+
+fn bucket(client_id: u16, pos: u64, len: u64) -> AudioData {
+    let marker = if pos == 1 { BurstMarker::Start } else { BurstMarker::Continue };
+    AudioData { client_id: client_id, pos: pos, format: format(), burst: Burst { id: 1, marker: marker }, priority: Priority::Normal, destination: None, data: samples(len) }
+}
+
+fn ring_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ring_buffer");
+    group.throughput(Throughput::Elements(WRITE_BUCKET_LEN));
+    group.bench_function("store_samples", |b| {
+        let mut ring = RingBuffer::new(RING_BUFFER_LEN, SPARE_LEN, IDLE_THRESHOLD);
+        let data = samples(WRITE_BUCKET_LEN);
+        let mut pos = 1;
+        b.iter(|| {
+            ring.store_samples(pos, &data).unwrap();
+            pos += WRITE_BUCKET_LEN;
+        });
+    });
+    group.throughput(Throughput::Elements(READ_BUCKET_LEN as u64));
+    group.bench_function("get_next", |b| {
+        b.iter_custom(|iters| {
+            let mut ring = RingBuffer::new(RING_BUFFER_LEN, SPARE_LEN, IDLE_THRESHOLD);
+            let data = samples(READ_BUCKET_LEN as u64);
+            // the reader starts once more than the spare area is filled
+            let mut pos = 1;
+            while pos <= SPARE_LEN + 1 {
+                ring.store_samples(pos, &data).unwrap();
+                pos += READ_BUCKET_LEN as u64;
+            }
+            let mut elapsed = Duration::new(0, 0);
+            for _ in 0..iters {
+                ring.store_samples(pos, &data).unwrap();
+                pos += READ_BUCKET_LEN as u64;
+                let start = Instant::now();
+                criterion::black_box(ring.get_next(READ_BUCKET_LEN as u64));
+                elapsed += start.elapsed();
+            }
+            elapsed
+        });
+    });
+    group.finish();
+}
+
+// only mixing is timed, the buckets of every stream are stored in between
+fn audio_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("audio_buffer_get_next");
+    group.throughput(Throughput::Elements(READ_BUCKET_LEN as u64));
+    for &streams in &[1_u16, 4, 16, 64] {
+        group.bench_function(format!("{}_streams", streams), |b| {
+            b.iter_custom(|iters| {
+                let mut buffer = AudioBuffer::new(RING_BUFFER_LEN, SPARE_LEN, IDLE_THRESHOLD, format());
+                let mut pos = 1;
+                let mut store = |buffer: &mut AudioBuffer, pos: &mut u64| {
+                    for client_id in 1..streams + 1 {
+                        buffer.store_data(bucket(client_id, *pos, READ_BUCKET_LEN as u64)).unwrap();
+                    }
+                    *pos += READ_BUCKET_LEN as u64;
+                };
+                while pos <= SPARE_LEN + 1 {
+                    store(&mut buffer, &mut pos);
+                }
+                let mut elapsed = Duration::new(0, 0);
+                for _ in 0..iters {
+                    store(&mut buffer, &mut pos);
+                    let start = Instant::now();
+                    criterion::black_box(buffer.get_next(READ_BUCKET_LEN));
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encoding");
+    let payload = PayloadPacket::new(bucket(1, 1, WRITE_BUCKET_LEN), 1, 0);
+    let advertisement: SendablePackets<AudioData> = SendablePackets::AdvertisementPacket(AdvertisementPacket::new(&payload, 1));
+    let payload: SendablePackets<AudioData> = SendablePackets::PayloadPacket(payload);
+    let encoded_payload = packet_layer::encode(&payload).unwrap();
+    let encoded_advertisement = packet_layer::encode(&advertisement).unwrap();
+    group.throughput(Throughput::Bytes(encoded_payload.len() as u64));
+    group.bench_function("encode_payload", |b| b.iter(|| packet_layer::encode(criterion::black_box(&payload)).unwrap()));
+    group.bench_function("decode_payload", |b| b.iter(|| packet_layer::decode::<AudioData>(criterion::black_box(&encoded_payload)).unwrap()));
+    group.throughput(Throughput::Bytes(encoded_advertisement.len() as u64));
+    group.bench_function("encode_advertisement", |b| b.iter(|| packet_layer::encode(criterion::black_box(&advertisement)).unwrap()));
+    group.bench_function("decode_advertisement", |b| b.iter(|| packet_layer::decode::<AudioData>(criterion::black_box(&encoded_advertisement)).unwrap()));
+    group.finish();
+}
+
+// payloads from other nodes: decoded, cached, advertised and delivered
+fn worker_loop(c: &mut Criterion) {
+    const BATCH: u64 = 32;
+    let (datagrams, incoming) = channel();
+    let (_local, local_payloads) = channel::<PayloadPacket<AudioData>>();
+    let (delivered, received): (Sender<PayloadPacket<AudioData>>, _) = channel();
+    let broadcast = "127.255.255.255:1337".parse().unwrap();
+    thread::spawn(move || {
+        let socket = MemorySocket::new(incoming);
+        packet_layer::worker_loop(broadcast, 2, socket, local_payloads, delivered);
+    });
+
+    let mut group = c.benchmark_group("worker_loop");
+    group.throughput(Throughput::Elements(BATCH));
+    let mut source = 0;
+    group.bench_function("receive_payloads", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::new(0, 0);
+            for _ in 0..iters {
+                // every payload has a new id, otherwise it would be ignored as a duplicate
+                let batch: Vec<Vec<u8>> = (0..BATCH).map(|_| {
+                    source += 1;
+                    packet_layer::encode(&SendablePackets::PayloadPacket(PayloadPacket::new(bucket(1, 1, WRITE_BUCKET_LEN), source, 0))).unwrap()
+                }).collect();
+                let start = Instant::now();
+                for datagram in batch {
+                    datagrams.send(datagram).unwrap();
+                }
+                for _ in 0..BATCH {
+                    received.recv().unwrap();
+                }
+                elapsed += start.elapsed();
+            }
+            elapsed
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ring_buffer, audio_buffer, encoding, worker_loop);
+criterion_main!(benches);
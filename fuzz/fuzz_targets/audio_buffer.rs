@@ -0,0 +1,42 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate walkie_talkie_pi;
+
+use walkie_talkie_pi::audio::{AudioBuffer, AudioData, Bandwidth, Burst, BurstMarker, Priority, SampleFormat, StreamFormat};
+
+// Feeds arbitrary streams, as they could arrive from the network, into the mixer and plays them.
+// Nothing of it may panic and every bucket played has the requested length.
+fuzz_target!(|ops: Vec<(bool, u8, u64, u32, u16, u8, u8, u16)>| {
+    let format = StreamFormat { sample_rate: 16000, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full };
+    let mut buffer = AudioBuffer::new(4096, 256, 1000000, format);
+    for (play, client_id, pos, sample_rate, channels, burst, marker, len) in ops {
+        if play {
+            if let Some(data) = buffer.get_next(512) {
+                assert_eq!(data.len(), 512);
+            }
+            continue;
+        }
+        let sample_rate = match sample_rate % 4 {
+            0 => 16000,
+            1 => 8000,
+            2 => 44100,
+            _ => sample_rate,
+        };
+        let marker = match marker % 3 {
+            0 => BurstMarker::Start,
+            1 => BurstMarker::Continue,
+            _ => BurstMarker::End,
+        };
+        let data = AudioData {
+            client_id: client_id as u16,
+            pos: pos % 100000,
+            format: StreamFormat { sample_rate: sample_rate, channels: channels % 4, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full },
+            burst: Burst { id: (burst % 4) as u32, marker: marker },
+            priority: Priority::Normal,
+            destination: None,
+            data: vec![1000; len as usize % 6000],
+        };
+        let _ = buffer.store_data(data);
+    }
+});
@@ -0,0 +1,49 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate walkie_talkie_pi;
+
+use std::collections::HashMap;
+use walkie_talkie_pi::audio::RingBuffer;
+
+// Drives arbitrary sequences of stores and reads. The reference model remembers the last sample
+// stored at every position, every sample returned by get_next() has to match it (or be silence
+// if nothing was stored there).
+fuzz_target!(|input: (u16, u16, u16, Vec<(bool, bool, u64, i32, u16)>)| {
+    let (buf_len, spare, read_len, ops) = input;
+    let buf_len = 2 + buf_len as u64 % 1024;
+    let spare = spare as u64 % buf_len;
+    if spare + 1 >= buf_len {
+        return;
+    }
+    let read_len = 1 + read_len as u64 % (buf_len - spare - 1);
+    let mut ring = RingBuffer::new(buf_len as u32, spare, 1000000);
+    let mut model: HashMap<u64, i16> = HashMap::new();
+    let mut max = 0_u64;
+
+    for (index, (read, relative, pos, delta, len)) in ops.into_iter().enumerate() {
+        if read {
+            if let Some(data) = ring.get_next(read_len) {
+                assert_eq!(data.len() as u64, read_len);
+                let start = ring.next_pos().unwrap() - read_len;
+                for (offset, val) in data.iter().enumerate() {
+                    let expected = model.get(&(start + offset as u64)).cloned().unwrap_or(0);
+                    assert_eq!(*val, expected, "sample at pos {}", start + offset as u64);
+                }
+            }
+        } else {
+            // mostly positions around the newest data, like a real stream with jitter
+            let pos = if relative { (max as i64 + delta as i64 % (2 * buf_len as i64)).max(0) as u64 } else { pos };
+            let value = 1 + (index % 30000) as i16;
+            let samples = vec![value; len as usize % (buf_len as usize + 8)];
+            if ring.store_samples(pos, &samples).is_ok() {
+                for offset in 0..samples.len() as u64 {
+                    model.insert(pos + offset, value);
+                }
+                if !samples.is_empty() {
+                    max = max.max(pos + samples.len() as u64 - 1);
+                }
+            }
+        }
+    }
+});
@@ -4,20 +4,126 @@ use rand::Rng;
 use std::collections::HashMap;
 use std::thread;
 use std::sync;
-use std::ffi::CString;
 use std;
 use alsa::{Direction, ValueOr};
+use alsa::Error as AlsaError;
+use echo::Duplex;
+use resample::Resampler;
+use packet_layer::{Prioritized, Versioned};
+use mux::{MessageKind, KIND_AUDIO};
 use alsa::pcm::{PCM, HwParams, Format, Access, Frames};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use stats;
+use devices::{self, Fault};
+use handoff::{self, Consumer, Producer};
 
 
+// limits for streams that have to be converted, prevent overflows in the position mapping
+// and huge resampler states
+const MAX_SAMPLE_RATE: u32 = 384000;
+const MAX_CHANNELS: u16 = 8;
+const MAX_CONVERTED_POS: u64 = 1 << 40;
+// maximum number of streams mixed at the same time, idle ones are dropped to make room
+const MAX_STREAMS: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum SampleFormat {
+    S16,  // signed 16 bit, native endian
+}
+
+// Voice bandwidth presets, lower bandwidths are transmitted at a lower sample rate.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Bandwidth {
+    Narrow,  // 8 kHz, telephone quality
+    Wide,    // 16 kHz
+    Full,    // the capture rate, i.e. 44.1 or 48 kHz
+}
+
+impl Bandwidth {
+    pub fn parse(name: &str) -> Result<Bandwidth, String> {
+        match name {
+            "narrow" => Ok(Bandwidth::Narrow),
+            "wide" => Ok(Bandwidth::Wide),
+            "full" => Ok(Bandwidth::Full),
+            _ => Err(format!("unknown bandwidth '{}' (expected narrow, wide or full)", name)),
+        }
+    }
+
+    pub fn sample_rate(&self, capture_rate: u32) -> u32 {
+        match *self {
+            Bandwidth::Narrow => std::cmp::min(8000, capture_rate),
+            Bandwidth::Wide => std::cmp::min(16000, capture_rate),
+            Bandwidth::Full => capture_rate,
+        }
+    }
+}
+
+// Describes the samples of an AudioData bucket, so that streams recorded with a different
+// configuration can be converted to the local playback format.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u16,   // samples of all channels are interleaved
+    pub sample_format: SampleFormat,
+    pub bandwidth: Bandwidth,  // preset the sender chose, informational only
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum BurstMarker {
+    Start,     // first bucket of a transmission
+    Continue,
+    End,       // last bucket of a transmission, the sender stopped talking
+}
+
+// Frames a single transmission ("over"). Positions start at 1 again for every burst.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Burst {
+    pub id: u32,
+    pub marker: BurstMarker,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum Priority {
+    Normal,
+    High,       // relayed first by the packet layer
+    Emergency,  // additionally pre-empts the floor holder and is played even if playback is muted
+}
+
+impl Priority {
+    pub fn parse(name: &str) -> Result<Priority, String> {
+        match name {
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            "emergency" => Ok(Priority::Emergency),
+            _ => Err(format!("unknown priority '{}' (expected normal, high or emergency)", name)),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AudioData {
     pub client_id: u16,
     pub pos: u64,    // pos must be > 0 (because position 0 is considered to be initial (unset) value
+    pub format: StreamFormat,
+    pub burst: Burst,
+    pub priority: Priority,
+    pub destination: Option<u16>,  // node a private call is addressed to, None for everybody
     pub data: Vec<i16>,
 }
 
+impl Prioritized for AudioData {
+    fn is_urgent(&self) -> bool {
+        self.priority >= Priority::High
+    }
+}
+
+impl Versioned for AudioData {}
+
+impl MessageKind for AudioData {
+    const KIND: u8 = KIND_AUDIO;
+}
+
 pub struct RingBuffer {
     buf: Vec<i16>,
     next: u64,  // points to first element to read next, i.e. buf[next] was not yet read
@@ -45,7 +151,7 @@ impl RingBuffer {
         trace!("ringbuffer {}: next {}/{} max {}/{} spare {} overhead {} len {} min {}/{} last_update: {:?}", prefix, self.next, self.next % self.buf.len() as u64, self.max, self.max % self.buf.len() as u64, self.spare, self.max - self.next + 1, self.buf.len(), self.min, self.min % self.buf.len() as u64, self.last_update.elapsed());
     }
 
-    pub fn peek(&self, target_len: u64) -> PeekState {
+    fn peek(&self, target_len: u64) -> PeekState {
         self.debug_print("peek: ");  // DEBUG
         let buf_len = self.buf.len() as u64;
         // TODO: Combine if clauses (added to have debug output only)
@@ -59,7 +165,7 @@ impl RingBuffer {
             trace!("This buffer is idle, elapsed is {}", elapsed);
             return PeekState::Idle;
         }
-        if (self.next == 0) && (self.min > 0) && (self.max - self.min) < self.spare {
+        if (self.next == 0) && (self.min > 0) && (self.max - self.min) <= self.spare {
             trace!("not enough data added yet. Return Ignore.");
             return PeekState::Ignore;
         }
@@ -69,6 +175,7 @@ impl RingBuffer {
         }
         if (self.next != 0) && (self.max - self.next < target_len)  {
             trace!("buffer has only {}, but need {}, return None", self.max - self.next, target_len);
+            stats::RING_UNDERRUNS.inc();
             return PeekState::None;
         }
         let avail = if self.next == 0 {self.max - self.min} else {self.max - self.next};
@@ -79,13 +186,12 @@ impl RingBuffer {
     pub fn get_next(&mut self, target_len: u64) -> Option<Vec<i16>> {
         assert!(self.spare + target_len < self.buf.len() as u64);
         self.debug_print("get_next: ");  // DEBUG
-        let buf_len = self.buf.len() as u64;
         // TODO: Combine if clauses (added to have debug output only)
         if self.max == 0 {
             trace!("no data yet added. Return None.");
             return None;
         }
-        if (self.next == 0) && (self.min > 0) && (self.max - self.min) < self.spare {
+        if (self.next == 0) && (self.min > 0) && (self.max - self.min) <= self.spare {
             trace!("not enough data added yet. Return None.");
             return None;
         }
@@ -104,158 +210,120 @@ impl RingBuffer {
             trace!("First call, setting next to {}", self.next);
         }
         let mut result = vec![0_i16;target_len as usize];
-        let start = (self.next % buf_len) as usize;
-        let mut end_excl = ((self.next + target_len) % buf_len) as usize;  // buf[end_excl] is the first element not to be returned.
-        if end_excl == 0 {
-            end_excl = (buf_len + 1) as usize;
-        }
-        let zero = vec![0_i16;target_len as usize];
-        if start < end_excl {
-            // current bucket does not exceed buf size, thus we may copy in one piece:
-            trace!("get_next(): copy1 [0..{}] <- [{}..{}]", target_len, start, end_excl);
-            &result[0..target_len as usize].copy_from_slice(&self.buf[start..end_excl]);  // note: start..end excludes the element at end_excl
-            &self.buf[start..end_excl].copy_from_slice(&zero[0..target_len as usize]);  // note: start..end excludes the element at end_excl
-        }
-        else {
-            // current bucket exceeds buf size, thus copy in two pieces:
-            let len_first_part = buf_len as usize - start;
-            trace!("get_next(): copy2 [0..{}] <- [{}..{}]", len_first_part, start, buf_len);
-            trace!("get_next(): copy2 [{}..{}] <- [0..{}]", len_first_part, target_len, end_excl);
-            &result[0..len_first_part].copy_from_slice(&self.buf[start..buf_len as usize]);
-            &self.buf[start..buf_len as usize].copy_from_slice(&zero[0..len_first_part]);
-            &result[len_first_part..target_len as usize].copy_from_slice(&self.buf[0..end_excl]);
-            &self.buf[0..end_excl].copy_from_slice(&zero[len_first_part..target_len as usize]);
-        }
+        let next = self.next;
+        self.copy_slots(next, &mut result);
+        // read slots are cleared, so positions that are never stored play as silence
+        self.write_slots(next, &vec![0_i16;target_len as usize]);
         self.next = self.next + target_len;
         Some(result)
+    }
 
-    }        
-//*/    pub fn get_next(&mut self, target_len: u64) -> Option<Vec<i16>> {
-//*/        assert!(self.spare + target_len < self.buf.len() as u64);
-//*/        self.debug_print();  // DEBUG
-//*/        let buf_len = self.buf.len() as u64;
-//*/        // TODO: Combine if clauses (added to have debug output only)
-//*/        if self.max == 0 {
-//*/            trace!("no data yet added. Return None.");
-//*/            return None;
-//*/        }
-//*/        if self.max < self.spare {
-//*/            trace!("not enough data added yet. Return None.");
-//*/            return None;
-//*/        }
-//*/        if self.next == 0 {
-//*/            // first call -> set self.next to some sensitive value
-//*///            self.next = if self.max < buf_len {
-//*///                1
-//*///            }
-//*///            else {
-//*///                self.max - buf_len + 1
-//*///            };
-//*/
-//*/            // option 2:
-//*///                       self.next = if self.max > self.spare + target_len {
-//*///                self.max - self.spare - target_len + 1
-//*///            }
-//*///            else {
-//*///                1  // TODO
-//*///            };
-//*/            // option 3:
-//*/            if self.min > self.max - self.spare {
-//*/                self.next = self.min;
-//*/                trace!("First call, adjusting next to {}", self.next);
-//*/            }
-//*/            else {
-//*/                //self.next = self.min;
-//*/                trace!("First call but not enough space");
-//*/            }
-//*/            
-//*/        }
-//*/        if self.next > self.max - self.spare {
-//*/            trace!("not enough spare data. Return None.");
-//*/            return None;
-//*/        }
-//*/        let max_avail = ((self.max - self.spare) + 1) - self.next;   // Do not change computation order to prevent underflow!
-//*/        let len = if max_avail < target_len {
-//*/            trace!("Reducing length of result vector from {} to {}", target_len, max_avail);
-//*/            return None; // TILT
-//*/            //max_avail
-//*/        }
-//*/        else {
-//*/            target_len
-//*/        };
-//*/        assert!(len > 0);
-//*/        //let mut result = Vec::<i16>::with_capacity(len as usize);
-//*/        let mut result = vec![0_i16;len as usize];
-//*/        let start = (self.next % buf_len) as usize;
-//*/        let mut end_excl = ((self.next + len) % buf_len) as usize;  // buf[end_excl] is the first element not to be returned.
-//*/        if end_excl == 0 {
-//*/            end_excl = (buf_len + 1) as usize;
-//*/        }
-//*/        if start < end_excl {
-//*/            // current bucket does not exceed buf size, thus we may copy in one piece:
-//*/            trace!("get_next(): copy1 [0..{}] <- [{}..{}]", len, start, end_excl);
-//*/            &result[0..len as usize].copy_from_slice(&self.buf[start..end_excl]);  // note: start..end excludes the element at end_excl
-//*/        }
-//*/        else {
-//*/            // current bucket exceeds buf size, thus copy in two pieces:
-//*/            let len_first_part = buf_len as usize - start;
-//*/            trace!("get_next(): copy2 [0..{}] <- [{}..{}]", len_first_part, start, buf_len);
-//*/            trace!("get_next(): copy2 [{}..{}] <- [0..{}]", len_first_part, len, end_excl);
-//*/            &result[0..len_first_part].copy_from_slice(&self.buf[start..buf_len as usize]);
-//*/            &result[len_first_part..len as usize].copy_from_slice(&self.buf[0..end_excl]);
-//*/        }
-//*/        self.next = self.next + len;
-//*/        Some(result)
-//*/    }
-//*/
     fn store_data(&mut self, data: AudioData) -> Result<Option<()>, String> {
-        let buf_len = self.buf.len() as u64;
         trace!("store data from client {} at pos {} of len {}", data.client_id, data.pos, data.data.len());
-        assert!(data.data.len() < buf_len as usize);
-        if (self.next > 0) && (self.next >= data.pos + data.data.len() as u64) {
-            trace!("data with pos {} and length {} is beyond next at {}", data.pos, data.data.len(), self.next);
+        self.store_samples(data.pos, &data.data)
+    }
+
+    // appends len samples of silence, e.g. to flush the end of a transmission
+    fn pad(&mut self, len: u64) -> Result<Option<()>, String> {
+        let pos = self.max + 1;
+        self.store_samples(pos, &vec![0_i16; len as usize])
+    }
+
+    fn is_idle(&self) -> bool {
+        elapsed_ms(self.last_update) > self.idle_threshold as u64
+    }
+
+    // number of stored samples that were not read yet
+    pub fn depth(&self) -> u64 {
+        if self.max == 0 {
+            0
+        } else if self.next == 0 {
+            self.max + 1 - self.min
+        } else {
+            (self.max + 1).saturating_sub(self.next)
+        }
+    }
+
+    // position of the sample get_next() returns next, None before the first read
+    pub fn next_pos(&self) -> Option<u64> {
+        if self.next == 0 { None } else { Some(self.next) }
+    }
+
+    // copies the slots of positions pos.. into data, wrapping around at the end of buf
+    fn copy_slots(&self, pos: u64, data: &mut [i16]) {
+        let start = (pos % self.buf.len() as u64) as usize;
+        let len_first_part = std::cmp::min(data.len(), self.buf.len() - start);
+        trace!("copy_slots(): [0..{}] <- [{}..{}], rest from [0..]", len_first_part, start, start + len_first_part);
+        let (first, second) = data.split_at_mut(len_first_part);
+        first.copy_from_slice(&self.buf[start..start + len_first_part]);
+        second.copy_from_slice(&self.buf[..second.len()]);
+    }
+
+    // writes samples to the slots of positions pos.., wrapping around at the end of buf
+    fn write_slots(&mut self, pos: u64, samples: &[i16]) {
+        let start = (pos % self.buf.len() as u64) as usize;
+        let len_first_part = std::cmp::min(samples.len(), self.buf.len() - start);
+        trace!("write_slots(): [{}..{}] <- [0..{}], rest to [0..]", start, start + len_first_part, len_first_part);
+        self.buf[start..start + len_first_part].copy_from_slice(&samples[..len_first_part]);
+        self.buf[..samples.len() - len_first_part].copy_from_slice(&samples[len_first_part..]);
+    }
+
+    // pos and samples come from the network, so everything that does not fit is rejected instead of asserted
+    pub fn store_samples(&mut self, pos: u64, samples: &[i16]) -> Result<Option<()>, String> {
+        let buf_len = self.buf.len() as u64;
+        if samples.is_empty() {
+            return Ok(None);
+        }
+        if samples.len() as u64 >= buf_len {
+            return Err(format!("data of length {} does not fit into ring buffer of length {}", samples.len(), buf_len));
+        }
+        let end_excl = match pos.checked_add(samples.len() as u64) {
+            Some(end_excl) if pos > 0 => end_excl,
+            _ => return Err(format!("invalid data pos {} for length {}", pos, samples.len()))
+        };
+        if (self.next > 0) && (self.next >= end_excl) {
+            trace!("data with pos {} and length {} is beyond next at {}", pos, samples.len(), self.next);
+            stats::LATE_DATA.inc();
             return Ok(None);
         }
-        if (self.max > 0) && (data.pos <= self.max) && (data.pos + data.data.len() as u64 > self.max) {
-            trace!("error: data with pos {} and length {} crosses max at {}", data.pos, data.data.len(), self.max);
+        if (self.max > 0) && (pos <= self.max) && (end_excl > self.max) {
+            trace!("error: data with pos {} and length {} crosses max at {}", pos, samples.len(), self.max);
             return Err("mismatch data pos and len: Crosses max.".to_string());
         }
-        let len = data.data.len();
-        let start = (data.pos % buf_len) as usize;
-        let mut end_excl = ((data.pos + data.data.len() as u64) % buf_len) as usize;
-        if end_excl == 0 {
-            end_excl = (buf_len + 1) as usize;
+        // slots of older positions are already reused by newer data (or were read), so late data is cut off there
+        let oldest = if self.next > 0 { self.next } else { (self.max + 1).saturating_sub(buf_len) };
+        if end_excl <= oldest {
+            trace!("data with pos {} and length {} is older than {}", pos, samples.len(), oldest);
+            stats::LATE_DATA.inc();
+            return Ok(None);
         }
-        trace!("store_data(): start {}, end_excl {}", start, end_excl);
-        if start < end_excl {
-            // current bucket does not exceed buf size, thus we may copy in one piece:
-            trace!("store_data(): copy1 [{}..{}] <- [0..{}]", start, end_excl, len);
-            &self.buf[start..end_excl].copy_from_slice(&data.data[0..len as usize]);
+        let skip = oldest.saturating_sub(pos);
+        let (pos, samples) = (pos + skip, &samples[skip as usize..]);
+        if (self.max > 0) && (pos > self.max + 1) {
+            // the slots of missing positions still hold old samples, which must not be played
+            let gap_start = std::cmp::max(self.max + 1, end_excl.saturating_sub(buf_len));
+            trace!("store_data(): clearing gap from {} to {}", gap_start, pos);
+            self.write_slots(gap_start, &vec![0_i16; (pos - gap_start) as usize]);
         }
-        else {
-            // current bucket exceeds buf size, thus copy in two pieces:
-            let len_first_part = (buf_len - start as u64) as usize;
-            trace!("store_data(): copy2 [{}..{}] <- [0..{}]", start, buf_len, len_first_part);
-            trace!("store_data(): copy2 [0..{}] <- [{}..{}]", end_excl, len_first_part, len);
-            &self.buf[start..buf_len as usize].copy_from_slice(&data.data[0..len_first_part]);
-            &self.buf[0..end_excl].copy_from_slice(&data.data[len_first_part..len as usize]);
-        }
-        if data.pos > self.max {  // Note: as data does not cross max (see check above), this condition is sufficient
-            self.max = data.pos + data.data.len() as u64 - 1;
+        self.write_slots(pos, samples);
+        if pos > self.max {  // Note: as data does not cross max (see check above), this condition is sufficient
+            self.max = end_excl - 1;
             trace!("new max: {}", self.max);
         }
         if (self.next == 0) && (self.min > 0) && (self.max - self.min >= buf_len) {
             trace!("max overrun while self.next == 0. (max {} min {} buf_len {}). Setting min = {}", self.max, self.min, buf_len, self.max - buf_len + 1);
+            stats::RING_OVERRUNS.inc();
             self.min = self.max - buf_len + 1
         }
-        if (self.next == 0) && ( (self.min == 0) || (self.min > data.pos) ) {
-            trace!("Setting min to {}", data.pos);
-            self.min = data.pos;
+        if (self.next == 0) && ( (self.min == 0) || (self.min > pos) ) {
+            trace!("Setting min to {}", pos);
+            self.min = pos;
         }
         if (self.next != 0) && (self.max - self.next >= buf_len) {
             // TODO: Set self.next to which value here?
             self.next = self.max - buf_len + 1;
             trace!("next overrun to {}", self.next);
+            stats::RING_OVERRUNS.inc();
         }
         self.last_update = Instant::now();
         //trace!("set last_update to {:?}", self.last_update);
@@ -264,35 +332,437 @@ impl RingBuffer {
 
 }
 
+// A beep of a single frequency, a frequency of 0 is a pause.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tone {
+    pub freq: f32,
+    pub duration_ms: u32,
+}
+
+impl Tone {
+    // parses a list of FREQ:MS pairs, e.g. "880:60,1320:90", or "off" for no tones
+    pub fn parse_list(spec: &str) -> Result<Vec<Tone>, String> {
+        if spec == "off" {
+            return Ok(Vec::new());
+        }
+        spec.split(',').map(|tone| {
+            let mut parts = tone.splitn(2, ':');
+            let freq = parts.next().unwrap_or("").trim().parse().map_err(|err| format!("invalid tone frequency in '{}': {}", tone, err))?;
+            let duration_ms = parts.next().ok_or(format!("missing tone duration in '{}'", tone))?.trim().parse().map_err(|err| format!("invalid tone duration in '{}': {}", tone, err))?;
+            Ok(Tone { freq: freq, duration_ms: duration_ms })
+        }).collect()
+    }
+}
+
+const TONE_AMPLITUDE: f32 = 8000.0;
+const TONE_FADE_MS: u32 = 5;  // fade in and out to avoid clicks
+
+// Renders tones in the playback format and mixes them into the player output.
+pub struct ToneGenerator {
+    format: StreamFormat,
+    pending: std::collections::VecDeque<i16>,
+}
+
+impl ToneGenerator {
+    pub fn new(format: StreamFormat) -> ToneGenerator {
+        ToneGenerator { format: format, pending: std::collections::VecDeque::new() }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn queue(&mut self, tones: &[Tone]) {
+        let rate = self.format.sample_rate as f32;
+        let channels = self.format.channels as usize;
+        for tone in tones {
+            let frames = (tone.duration_ms as f32 * rate / 1000.0) as usize;
+            let fade = std::cmp::max(1, std::cmp::min(frames / 2, (TONE_FADE_MS as f32 * rate / 1000.0) as usize));
+            for n in 0..frames {
+                let envelope = std::cmp::min(std::cmp::min(n, frames - 1 - n), fade) as f32 / fade as f32;
+                let val = if tone.freq > 0.0 {
+                    TONE_AMPLITUDE * envelope * (2.0 * std::f32::consts::PI * tone.freq * n as f32 / rate).sin()
+                } else {
+                    0.0
+                };
+                for _ in 0..channels {
+                    self.pending.push_back(val as i16);
+                }
+            }
+        }
+    }
+
+    pub fn mix_into(&mut self, data: &mut [i16]) {
+        for val in data.iter_mut() {
+            match self.pending.pop_front() {
+                Some(tone) => *val = val.saturating_add(tone),
+                None => break
+            }
+        }
+    }
+}
+
+// ========================================
+
+fn elapsed_ms(instant: Instant) -> u64 {
+    instant.elapsed().as_secs()*1000 + (instant.elapsed().subsec_nanos()/1000000) as u64
+}
+
+// ========================================
+
+// Converts the buckets of one stream to another format by mixing channels and resampling.
+// Contiguous buckets are resampled as one stream, after a gap the position is mapped from the
+// source to the target sample rate, so the bucket still ends up at the right place.
+struct StreamConverter {
+    from: StreamFormat,
+    to: StreamFormat,
+    resampler: Resampler,
+    next_pos: u64,   // pos of the bucket expected next
+    next_out: u64,   // pos the next converted samples are stored at
+}
+
+impl StreamConverter {
+    fn new(from: StreamFormat, to: StreamFormat) -> StreamConverter {
+        let resampler = Resampler::new(from.sample_rate, to.sample_rate, to.channels as usize);
+        StreamConverter { from: from, to: to, resampler: resampler, next_pos: 0, next_out: 0 }
+    }
+
+    fn mix_channels(&self, data: &[i16]) -> Vec<i16> {
+        let from_channels = self.from.channels as usize;
+        let to_channels = self.to.channels as usize;
+        if from_channels == to_channels {
+            return data.to_vec();
+        }
+        let mut result = Vec::with_capacity(data.len() / from_channels * to_channels);
+        for frame in data.chunks(from_channels) {
+            if to_channels == 1 {
+                result.push((frame.iter().map(|val| *val as i32).sum::<i32>() / frame.len() as i32) as i16);
+            } else {
+                result.extend((0..to_channels).map(|channel| frame[channel % frame.len()]));
+            }
+        }
+        result
+    }
+
+    fn convert(&mut self, data: AudioData) -> Result<AudioData, String> {
+        let from_channels = self.from.channels as u64;
+        let to_channels = self.to.channels as u64;
+        if (data.pos - 1) % from_channels != 0 || data.data.len() as u64 % from_channels != 0 {
+            return Err(format!("bucket at pos {} with len {} is not aligned to {} channels", data.pos, data.data.len(), from_channels));
+        }
+        if data.pos != self.next_pos {
+            trace!("stream of client {} is not contiguous at pos {}, expected {}", data.client_id, data.pos, self.next_pos);
+            let first_frame = (data.pos - 1) / from_channels;
+            self.resampler.reset();
+            self.next_out = first_frame * self.to.sample_rate as u64 / self.from.sample_rate as u64 * to_channels + 1;
+        }
+        self.next_pos = data.pos + data.data.len() as u64;
+        let mixed = self.mix_channels(&data.data);
+        let result = self.resampler.process(&mixed)?;
+        let pos = self.next_out;
+        self.next_out += result.len() as u64;
+        Ok(AudioData { client_id: data.client_id, pos: pos, format: self.to, burst: data.burst, priority: data.priority, destination: data.destination, data: result })
+    }
+}
+
 // ========================================
 
+// Transmission state of a single client
+struct BurstState {
+    id: u32,
+    priority: Priority,
+    previous: Option<u32>,  // id of the burst before, late buckets of it are dropped
+    ended: bool,            // end marker received, the ring buffer is removed once it is drained
+    finished: bool,         // completely played
+    started: Instant,
+    samples: u64,
+}
+
+impl BurstState {
+    fn new(id: u32, priority: Priority, previous: Option<u32>) -> BurstState {
+        BurstState { id: id, priority: priority, previous: previous, ended: false, finished: false, started: Instant::now(), samples: 0 }
+    }
+}
+
+// Reception statistics of a single client, kept across transmissions
+#[derive(Clone, Copy, Debug)]
+pub struct PeerStats {
+    pub client_id: u16,
+    pub level: i16,      // peak of the last bucket received
+    pub received: u64,   // samples
+    pub lost: u64,       // samples that were skipped and did not arrive late
+    pub buffered_ms: u64,  // audio waiting to be played
+    pub last_heard: Instant,
+}
+
+struct StreamStats {
+    level: i16,
+    received: u64,
+    lost: u64,
+    burst: u32,
+    next_pos: u64,  // position following the last bucket of burst
+    last_heard: Instant,
+}
+
+impl StreamStats {
+    fn new() -> StreamStats {
+        StreamStats { level: 0, received: 0, lost: 0, burst: 0, next_pos: 0, last_heard: Instant::now() }
+    }
+
+    fn note(&mut self, data: &AudioData) {
+        let len = data.data.len() as u64;
+        if self.next_pos == 0 || self.burst != data.burst.id {
+            // every burst starts at pos 1
+            self.burst = data.burst.id;
+            self.next_pos = 1;
+        }
+        if data.pos >= self.next_pos {
+            self.lost += data.pos - self.next_pos;
+            self.next_pos = data.pos + len;
+        } else {
+            self.lost = self.lost.saturating_sub(len);
+        }
+        self.received += len;
+        self.level = data.data.iter().fold(0_i16, |max, val| std::cmp::max(max, val.saturating_abs()));
+        self.last_heard = Instant::now();
+    }
+}
+
 pub struct AudioBuffer {
     buf_len: u32,
     spare: u64,
     idle_threshold: u32,
+    format: StreamFormat,  // local playback format, other streams are converted on arrival
     rings: HashMap<u16, RingBuffer>,
+    converters: HashMap<u16, StreamConverter>,
+    bursts: HashMap<u16, BurstState>,
+    stats: HashMap<u16, StreamStats>,
+    muted: bool,  // streams are still consumed, but silence is returned
+    user_muted: bool,  // like muted, but set by the user instead of the duplex logic
+    volume: u32,  // in percent, applied to the remote streams only
+    tones: ToneGenerator,
+    roger_beep: Vec<Tone>,  // played when a transmission is over
+    busy_tone: Vec<Tone>,   // played when the local user talks while somebody else is transmitting
+    call_tone: Vec<Tone>,   // played when a private call to this node starts
+    node_id: Option<u16>,   // private calls to other nodes are not played
 }
 
 impl AudioBuffer {
     // buf_len is needed in order to create silence and temp buffer
-    pub fn new(buf_len: u32, spare: u64, idle_threshold: u32) -> AudioBuffer {
-        AudioBuffer {buf_len: buf_len, spare: spare, idle_threshold: idle_threshold, rings: HashMap::new()}
+    pub fn new(buf_len: u32, spare: u64, idle_threshold: u32, format: StreamFormat) -> AudioBuffer {
+        AudioBuffer {buf_len: buf_len, spare: spare, idle_threshold: idle_threshold, format: format, rings: HashMap::new(), converters: HashMap::new(), bursts: HashMap::new(), stats: HashMap::new(), muted: false,
+            user_muted: false, volume: 100, tones: ToneGenerator::new(format), roger_beep: Vec::new(), busy_tone: Vec::new(), call_tone: Vec::new(), node_id: None}
+    }
+
+    // returns false if data belongs to a transmission that is already over
+    fn track_burst(&mut self, data: &AudioData) -> bool {
+        let client_id = data.client_id;
+        let burst = data.burst;
+        if let Some(state) = self.bursts.get_mut(&client_id) {
+            if state.id == burst.id {
+                return !state.finished;
+            }
+            if state.previous == Some(burst.id) {
+                trace!("dropping late data of transmission {} from client {}", burst.id, client_id);
+                return false;
+            }
+            if !state.ended {
+                info!("transmission {} from client {} ended without end marker after {} ms", state.id, client_id, elapsed_ms(state.started));
+            }
+        }
+        info!("transmission {} from client {} started ({:?} bandwidth, {:?} priority)", burst.id, client_id, data.format.bandwidth, data.priority);
+        if data.destination.is_some() {
+            warn!("incoming private call from client {}", client_id);
+            let tones = self.call_tone.clone();
+            self.tones.queue(&tones);
+        }
+        let previous = self.bursts.get(&client_id).map(|state| state.id);
+        self.bursts.insert(client_id, BurstState::new(burst.id, data.priority, previous));
+        // a new burst starts at pos 1 again, so drop the jitter state of the old one
+        self.rings.remove(&client_id);
+        self.converters.remove(&client_id);
+        true
+    }
+
+    fn finish_burst(&mut self, client_id: u16) {
+        self.rings.remove(&client_id);
+        self.converters.remove(&client_id);
+        if let Some(state) = self.bursts.get_mut(&client_id) {
+            state.finished = true;
+            info!("transmission {} from client {} finished after {} ms, {} samples", state.id, client_id, elapsed_ms(state.started), state.samples);
+        }
+        let tones = self.roger_beep.clone();
+        self.tones.queue(&tones);
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        if muted != self.muted {
+            debug!("playback muted: {}", muted);
+        }
+        self.muted = muted;
+    }
+
+    pub fn set_user_muted(&mut self, muted: bool) {
+        info!("playback muted by user: {}", muted);
+        self.user_muted = muted;
+    }
+
+    pub fn user_muted(&self) -> bool {
+        self.user_muted
+    }
+
+    pub fn set_volume(&mut self, percent: u32) {
+        info!("playback volume set to {}%", percent);
+        self.volume = percent;
+    }
+
+    pub fn volume(&self) -> u32 {
+        self.volume
+    }
+
+    // clients whose transmission is in progress
+    pub fn talkers(&self) -> Vec<u16> {
+        let mut talkers: Vec<u16> = self.bursts.iter().filter(|&(_, state)| !state.ended && !state.finished).map(|(id, _)| *id).collect();
+        talkers.sort();
+        talkers
+    }
+
+    // statistics of every client heard so far, ordered by client id
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        let samples_per_ms = std::cmp::max(1, self.format.sample_rate as u64 * self.format.channels as u64 / 1000);
+        let mut stats: Vec<PeerStats> = self.stats.iter().map(|(id, stats)| PeerStats {
+            client_id: *id,
+            level: stats.level,
+            received: stats.received,
+            lost: stats.lost,
+            buffered_ms: self.rings.get(id).map_or(0, |buffer| buffer.depth() / samples_per_ms),
+            last_heard: stats.last_heard,
+        }).collect();
+        stats.sort_by_key(|stats| stats.client_id);
+        stats
     }
 
     pub fn store_data(&mut self, data: AudioData) -> Result<Option<()>, String> {
-        // TODO: Detect buffer that do not receive any more data
+        if data.destination.is_some() && data.destination != self.node_id {
+            trace!("dropping private call from client {} to {:?}", data.client_id, data.destination);
+            return Ok(None);
+        }
+        if !self.track_burst(&data) {
+            return Ok(None);
+        }
+        self.stats.entry(data.client_id).or_insert_with(StreamStats::new).note(&data);
+        let ended = data.burst.marker == BurstMarker::End;
+        if !self.rings.contains_key(&data.client_id) && !self.converters.contains_key(&data.client_id) && !self.make_room() {
+            return Err(format!("too many streams, dropping data of client {}", data.client_id));
+        }
+        let data = if data.format != self.format {
+            if data.format.sample_rate == 0 || data.format.sample_rate > MAX_SAMPLE_RATE || data.format.channels == 0 || data.format.channels > MAX_CHANNELS || data.pos == 0 || data.pos > MAX_CONVERTED_POS {
+                return Err(format!("invalid stream format {:?} at pos {}", data.format, data.pos));
+            }
+            let format = self.format;
+            let converter = self.converters.entry(data.client_id).or_insert_with(|| StreamConverter::new(data.format, format));
+            if converter.from != data.format {
+                debug!("client {} changed stream format from {:?} to {:?}", data.client_id, converter.from, data.format);
+                *converter = StreamConverter::new(data.format, format);
+                self.rings.remove(&data.client_id);
+            }
+            converter.convert(data)?
+        } else {
+            data
+        };
+        let client_id = data.client_id;
+        if let Some(state) = self.bursts.get_mut(&client_id) {
+            state.samples += data.data.len() as u64;
+            state.ended |= ended;
+        }
         // Note: Since we automatically add new clients, each client will use a ringbuffer with the same configuration
-        if ! self.rings.contains_key(&data.client_id) {
-            trace!("store_data() called for non-existing client id {}", data.client_id);
-            self.rings.insert(data.client_id, RingBuffer::new(self.buf_len, self.spare, self.idle_threshold));
+        if ! self.rings.contains_key(&client_id) {
+            if data.data.is_empty() {
+                return Ok(None);
+            }
+            trace!("store_data() called for non-existing client id {}", client_id);
+            self.rings.insert(client_id, RingBuffer::new(self.buf_len, self.spare, self.idle_threshold));
         }
-        match self.rings.get_mut(&data.client_id) {
-            Some(buffer) => buffer.store_data(data),
+        let buffer = match self.rings.get_mut(&client_id) {
+            Some(buffer) => buffer,
             None => panic!("where is the client gone?!?")
+        };
+        let result = if data.data.is_empty() { Ok(None) } else { buffer.store_data(data) };
+        if ended {
+            // flush the end of the transmission, even if it is shorter than the spare area
+            trace!("end of transmission from client {}, padding with {} samples", client_id, self.spare);
+            buffer.pad(self.spare)?;
+        }
+        result
+    }
+
+    // drops idle streams if there are too many, returns false if no new stream can be added
+    fn make_room(&mut self) -> bool {
+        if self.rings.len() < MAX_STREAMS && self.converters.len() < MAX_STREAMS {
+            return true;
+        }
+        let idle: Vec<u16> = self.rings.iter().filter(|&(_, buffer)| buffer.is_idle()).map(|(id, _)| *id).collect();
+        for id in idle {
+            debug!("dropping idle stream of client {}", id);
+            self.rings.remove(&id);
+            self.converters.remove(&id);
+        }
+        let rings = &self.rings;
+        self.converters.retain(|id, _| rings.contains_key(id));
+        self.rings.len() < MAX_STREAMS && self.converters.len() < MAX_STREAMS
+    }
+
+    pub fn set_roger_beep(&mut self, tones: Vec<Tone>) {
+        self.roger_beep = tones;
+    }
+
+    pub fn set_busy_tone(&mut self, tones: Vec<Tone>) {
+        self.busy_tone = tones;
+    }
+
+    pub fn set_call_tone(&mut self, tones: Vec<Tone>) {
+        self.call_tone = tones;
+    }
+
+    pub fn set_node_id(&mut self, node_id: u16) {
+        self.node_id = Some(node_id);
+    }
+
+    // true if some other client is in the middle of a transmission
+    pub fn channel_busy(&self) -> bool {
+        let idle_threshold = self.idle_threshold as u64;
+        self.bursts.iter().any(|(id, state)| !state.ended && !state.finished && self.rings.get(id).map_or(false, |buffer| elapsed_ms(buffer.last_update) < idle_threshold))
+    }
+
+    pub fn play_busy_tone(&mut self) {
+        if !self.tones.is_playing() {
+            let tones = self.busy_tone.clone();
+            self.tones.queue(&tones);
         }
     }
 
+    // called when the local user starts talking, plays the busy tone if somebody else is talking
+    pub fn check_busy(&mut self) -> bool {
+        let busy = self.channel_busy();
+        if busy {
+            info!("channel is busy");
+            self.play_busy_tone();
+        }
+        busy
+    }
+
     pub fn get_next(&mut self, len: u32) -> Option<Vec<i16>> {
+        let mixed = self.mix_streams(len);
+        if !self.tones.is_playing() {
+            return mixed;
+        }
+        // tones are local feedback, so they are played even if the remote streams are muted
+        let mut vec = mixed.unwrap_or_else(|| vec![0_i16; len as usize]);
+        self.tones.mix_into(&mut vec);
+        Some(vec)
+    }
+
+    fn mix_streams(&mut self, len: u32) -> Option<Vec<i16>> {
         let mut vec = vec![0_i16;len as usize];
 
         //let mut rng = rand::thread_rng();
@@ -300,6 +770,18 @@ impl AudioBuffer {
         //    *val = rng.gen::<i16>() / 10;
         //}
 
+        // remove transmissions that ended and are played completely
+        let drained: Vec<u16> = self.rings.iter()
+            .filter(|&(id, buffer)| self.bursts.get(id).map_or(false, |state| state.ended) && match buffer.peek(len as u64) {
+                PeekState::Avail(_) => false,
+                _ => true
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in drained {
+            self.finish_burst(id);
+        }
+
         let scaling = 1 + self.rings.len() as i16;
         let mut some = false;
         if self.rings.len() == 0 {
@@ -339,10 +821,17 @@ impl AudioBuffer {
                 }
             };
             some = true;
+            let emergency = self.bursts.get(id).map_or(false, |state| state.priority == Priority::Emergency);
+            if (self.muted || self.user_muted) && !emergency {
+                continue;
+            }
+            // emergency transmissions are never played quieter than normal
+            let volume = if emergency { std::cmp::max(self.volume, 100) } else { self.volume } as i32;
             //trace!(" Got data for client {}", id);
             for (pos, val) in data.iter().enumerate() {
                 //trace!("Adding {} to {} at pos {}", *val, vec[pos], pos);
-                vec[pos] += *val / scaling;
+                let scaled = *val as i32 * volume / 100 / scaling as i32;
+                vec[pos] = (vec[pos] as i32 + scaled).max(i16::min_value() as i32).min(i16::max_value() as i32) as i16;
             }
             //trace!("done");
         }
@@ -365,211 +854,481 @@ pub struct AudioConfig<'a> {
     pub sample_rate: u32,
 }
 
+impl<'a> AudioConfig<'a> {
+    pub fn format(&self) -> StreamFormat {
+        StreamFormat { sample_rate: self.sample_rate, channels: self.num_channels as u16, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full }
+    }
+}
+
 // ========================================
 
-#[allow(dead_code)]
-pub struct Player {
+// minimum and maximum wait between attempts to reopen a lost device
+const REOPEN_BACKOFF_MIN_MS: u64 = 100;
+const REOPEN_BACKOFF_MAX_MS: u64 = 5000;
+// longest wait for the playback device to make room, a device that does not wake up is written to anyway
+const WAIT_TIMEOUT_MS: u32 = 1000;
+// buckets received but not yet taken by the player thread
+const PLAY_QUEUE_LEN: usize = 256;
+
+// Configures an opened device, returns it prepared together with its actual rate.
+fn configure_pcm(pcm: PCM, num_channels: u32, sample_rate: u32) -> Result<(PCM, u32), Box<dyn std::error::Error>> {
+    let actual_rate;
+    // The following block is needed to prevent borrow compile error because of hwp
+    {
+        let hwp = HwParams::any(&pcm)?;
+        hwp.set_channels(num_channels)?;
+        actual_rate = hwp.set_rate_near(sample_rate, ValueOr::Nearest)?;
+        hwp.set_format(Format::s16())?;
+        hwp.set_access(Access::RWInterleaved)?;
+        trace!("Buffersize: {:?}", hwp.get_buffer_size());
+        pcm.hw_params(&hwp)?;
+    }
+    pcm.prepare()?;
+    Ok((pcm, actual_rate))
+}
+
+fn open_pcm(devname: &str, num_channels: u32, sample_rate: u32, direction: Direction) -> Result<(PCM, u32), Box<dyn std::error::Error>> {
+    configure_pcm(devices::open(devname, direction)?, num_channels, sample_rate)
+}
+
+// An open device together with what is needed to open it again after it was lost. Devices are
+// opened non-blocking (see devices::open), so reading and writing wait for the device here.
+struct Stream {
     pcm: PCM,
-    sample_rate: u32,  // currently not used
+    direction: Direction,
+    devname: String,
+    num_channels: u32,
+    sample_rate: u32,  // the configured rate
 }
 
-impl Player {
-    pub fn new(config: &AudioConfig) -> Result<Player, Box<std::error::Error>> {
-        let cs = CString::new(config.devname)?;
-        let pcm = PCM::open(&*cs, Direction::Playback, false)?;
-        let sample_rate;
-        {
-            let hwp = HwParams::any(&pcm)?;
-            hwp.set_channels(config.num_channels)?;
-            sample_rate = hwp.set_rate_near(config.sample_rate, ValueOr::Nearest)?;
-            if sample_rate != config.sample_rate {
-                trace!("Sample rate was changed from {} to {}", config.sample_rate, sample_rate);
+impl Stream {
+    // takes pcm as opened by devices::select(), returns the stream and the actual rate of the device
+    fn new(pcm: PCM, config: &AudioConfig, direction: Direction) -> Result<(Stream, u32), Box<dyn std::error::Error>> {
+        let (pcm, actual_rate) = configure_pcm(pcm, config.num_channels, config.sample_rate)?;
+        devices::health(direction).set(devices::State::Running);
+        let stream = Stream { pcm: pcm, direction: direction, devname: config.devname.to_string(), num_channels: config.num_channels, sample_rate: config.sample_rate };
+        Ok((stream, actual_rate))
+    }
+
+    // Makes the stream usable again after err. Blocks until the device could be reopened if it
+    // was lost and returns the actual rate of the device, which may have changed then.
+    fn recover(&mut self, err: AlsaError, actual_rate: u32) -> u32 {
+        match devices::classify(&err) {
+            Fault::Xrun => {
+                if self.direction == Direction::Playback { stats::XRUNS.inc() } else { stats::CAPTURE_OVERRUNS.inc() }
+                debug!("{:?} xrun on {}", self.direction, self.devname);
+            },
+            Fault::Suspended => info!("{:?} device {} was suspended, resuming", self.direction, self.devname),
+            Fault::Lost => return self.reopen(err),
+            Fault::Other => warn!("{:?} device {} failed: {}, recovering", self.direction, self.devname, err),
+        }
+        match self.pcm.recover(err.code(), true) {
+            Ok(()) => actual_rate,
+            Err(e) => self.reopen(e),
+        }
+    }
+
+    // writes all of data, unless the device fails
+    fn write(&self, data: &[i16]) -> Result<(), AlsaError> {
+        let io = self.pcm.io_i16()?;
+        let mut written = 0;
+        while written < data.len() {
+            match io.writei(&data[written..]) {
+                Ok(frames) => written += frames * self.num_channels as usize,
+                Err(ref err) if devices::would_block(err) => { self.pcm.wait(Some(WAIT_TIMEOUT_MS))?; },
+                Err(err) => return Err(err),
             }
-            hwp.set_format(Format::s16())?;
-            hwp.set_access(Access::RWInterleaved)?;
-            trace!("Buffersize: {:?}", hwp.get_buffer_size());
-            pcm.hw_params(&hwp)?;
-        };
-        pcm.prepare()?;
-        Ok(Player { pcm: pcm, sample_rate: sample_rate } )
+        }
+        Ok(())
     }
 
-    pub fn get_remain(&self) -> Frames {
-        let total = match self.pcm.status() {
-            Ok(val) => val.get_avail_max(),
-            Err(e) => {
-                trace!("******ERROR: get_avail_max() returned error {}", e);
-                self.pcm.prepare().unwrap();
-                return 0  // TODO: Is this OK?
+    // fills all of buffer, unless the device fails
+    fn read(&self, buffer: &mut [i16]) -> Result<(), AlsaError> {
+        let io = self.pcm.io_i16()?;
+        let mut read = 0;
+        while read < buffer.len() {
+            match io.readi(&mut buffer[read..]) {
+                Ok(frames) => read += frames * self.num_channels as usize,
+                Err(ref err) if devices::would_block(err) => { self.pcm.wait(Some(WAIT_TIMEOUT_MS))?; },
+                Err(err) => return Err(err),
             }
-        };
-        if total == 0 {
-            return 0
-        }
-        let avail = match self.pcm.avail_update() {
-            Ok(val) => val,
-            Err(e) => {
-                trace!("******ERROR: avail_update returned error {}, call prepare", e);
-                self.pcm.prepare().unwrap();
-                return 0
+        }
+        Ok(())
+    }
+
+    // samples (at the configured rate, all channels) waiting in the device to be played or read
+    fn queued(&self, actual_rate: u32) -> usize {
+        match self.pcm.avail_delay() {
+            Ok((_, delay)) => (std::cmp::max(delay, 0) as u64 * self.num_channels as u64 * self.sample_rate as u64 / actual_rate as u64) as usize,
+            Err(_) => 0,
+        }
+    }
+
+    fn reopen(&mut self, err: AlsaError) -> u32 {
+        error!("{:?} device {} failed: {}, reopening it", self.direction, self.devname, err);
+        let health = devices::health(self.direction);
+        health.set(devices::State::Lost);
+        let mut backoff = REOPEN_BACKOFF_MIN_MS;
+        loop {
+            thread::sleep(Duration::from_millis(backoff));
+            match open_pcm(&self.devname, self.num_channels, self.sample_rate, self.direction) {
+                Ok((pcm, actual_rate)) => {
+                    info!("{:?} device {} reopened", self.direction, self.devname);
+                    stats::DEVICE_REOPENS.inc();
+                    health.set(devices::State::Running);
+                    self.pcm = pcm;
+                    return actual_rate;
+                },
+                Err(e) => {
+                    debug!("cannot reopen {:?} device {}: {}, trying again in {} ms", self.direction, self.devname, e, backoff);
+                    backoff = std::cmp::min(2 * backoff, REOPEN_BACKOFF_MAX_MS);
+                },
             }
-        };
-        trace!("Current audio buffer status: status.get_avail_max: {}, avail_update: {}, status.get_avail: {}, total-avail {}", total, avail, self.pcm.status().unwrap().get_avail(), total-avail);
-        total - avail
+        }
+    }
+}
+
+// converts from the configured rate if the device does not support it
+fn playback_resampler(sample_rate: u32, actual_rate: u32, num_channels: u32) -> Option<Resampler> {
+    if actual_rate != sample_rate {
+        info!("Playback device does not support {} Hz, resampling to {} Hz", sample_rate, actual_rate);
+        Some(Resampler::new(sample_rate, actual_rate, num_channels as usize))
+    } else {
+        None
+    }
+}
+
+// Received buckets go to the player thread through this queue, so whoever receives them never
+// waits for the mixing.
+pub fn play_queue() -> (Producer<AudioData>, Consumer<AudioData>) {
+    handoff::channel(PLAY_QUEUE_LEN)
+}
+
+pub fn queue_for_playback(queue: &mut Producer<AudioData>, data: AudioData) {
+    if let Err(data) = queue.push(data) {
+        stats::PLAY_QUEUE_DROPS.inc();
+        trace!("play queue is full, dropping data of client {} at pos {}", data.client_id, data.pos);
+    }
+}
+
+// What others change about playback and what they get to know about it. The player thread owns
+// the AudioBuffer, settings reach it through atomics and it publishes its status once per bucket,
+// so neither side ever waits for the other's work.
+pub struct PlaybackControl {
+    user_muted: AtomicBool,
+    volume: AtomicUsize,
+    busy_tone: AtomicBool,  // requested, queued by the player thread with the next bucket
+    channel_busy: AtomicBool,
+    status: sync::Mutex<PlaybackStatus>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PlaybackStatus {
+    pub talkers: Vec<u16>,
+    pub peers: Vec<PeerStats>,
+}
+
+impl PlaybackControl {
+    fn new() -> PlaybackControl {
+        PlaybackControl { user_muted: AtomicBool::new(false), volume: AtomicUsize::new(100), busy_tone: AtomicBool::new(false), channel_busy: AtomicBool::new(false), status: sync::Mutex::new(PlaybackStatus::default()) }
+    }
+
+    pub fn set_user_muted(&self, muted: bool) {
+        self.user_muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn user_muted(&self) -> bool {
+        self.user_muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_volume(&self, percent: u32) {
+        self.volume.store(percent as usize, Ordering::Relaxed);
+    }
+
+    pub fn volume(&self) -> u32 {
+        self.volume.load(Ordering::Relaxed) as u32
+    }
+
+    pub fn play_busy_tone(&self) {
+        self.busy_tone.store(true, Ordering::Relaxed);
+    }
+
+    // as of the last bucket mixed, see AudioBuffer::channel_busy
+    pub fn channel_busy(&self) -> bool {
+        self.channel_busy.load(Ordering::Relaxed)
+    }
+
+    // called when the local user starts talking, plays the busy tone if somebody else is talking
+    pub fn check_busy(&self) -> bool {
+        let busy = self.channel_busy();
+        if busy {
+            info!("channel is busy");
+            self.play_busy_tone();
+        }
+        busy
+    }
+
+    // as of the last bucket mixed
+    pub fn status(&self) -> PlaybackStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+// The mixing state of the player thread: takes the queued buckets, applies the settings of the
+// PlaybackControl and mixes the next bucket.
+pub struct Mixer {
+    buffer: AudioBuffer,
+    queue: Consumer<AudioData>,
+    read_bucket_len: u32,
+    control: sync::Arc<PlaybackControl>,
+    duplex: Option<sync::Arc<sync::Mutex<Duplex>>>,
+}
+
+impl Mixer {
+    pub fn new(buffer: AudioBuffer, queue: Consumer<AudioData>, read_bucket_len: u32) -> Mixer {
+        let control = sync::Arc::new(PlaybackControl::new());
+        control.set_user_muted(buffer.user_muted());
+        control.set_volume(buffer.volume());
+        Mixer { buffer: buffer, queue: queue, read_bucket_len: read_bucket_len, control: control, duplex: None }
+    }
+
+    // playback is muted while the duplex logic says so, and played buckets become the echo reference
+    pub fn with_duplex(mut self, duplex: sync::Arc<sync::Mutex<Duplex>>) -> Mixer {
+        self.duplex = Some(duplex);
+        self
+    }
+
+    pub fn control(&self) -> sync::Arc<PlaybackControl> {
+        self.control.clone()
+    }
+
+    // silence if nothing is to be played
+    pub fn next_bucket(&mut self) -> Vec<i16> {
+        while let Some(data) = self.queue.pop() {
+            if let Err(e) = self.buffer.store_data(data) {
+                trace!("Could not store: {:?}", e);
+            }
+        }
+        let control = &*self.control;
+        if control.user_muted() != self.buffer.user_muted() {
+            self.buffer.set_user_muted(control.user_muted());
+        }
+        if control.volume() != self.buffer.volume() {
+            self.buffer.set_volume(control.volume());
+        }
+        if control.busy_tone.swap(false, Ordering::Relaxed) {
+            self.buffer.play_busy_tone();
+        }
+        if let Some(ref duplex) = self.duplex {
+            self.buffer.set_muted(duplex.lock().unwrap().playback_muted());
+        }
+        let next = self.buffer.get_next(self.read_bucket_len);
+        control.channel_busy.store(self.buffer.channel_busy(), Ordering::Relaxed);
+        // a reader copying the status is not waited for, it gets the next one
+        if let Ok(mut status) = control.status.try_lock() {
+            status.talkers = self.buffer.talkers();
+            status.peers = self.buffer.peer_stats();
+        }
+        match next {
+            Some(data) => data,
+            None => {
+                trace!("nothing to play, writing silence");
+                vec![0_i16; self.read_bucket_len as usize]
+            }
+        }
+    }
+}
+
+pub struct Player {
+    stream: Stream,
+    sample_rate: u32,  // actual rate of the device
+    resampler: Option<Resampler>,  // converts from the configured rate if the device does not support it
+    fill: u32,  // samples at the configured rate wait_for_room() keeps queued in the device, 0 if not set
+}
+
+impl Player {
+    // pcm is the device named in config, opened for playback by devices::select()
+    pub fn new(config: &AudioConfig, pcm: PCM) -> Result<Player, Box<dyn std::error::Error>> {
+        let (stream, sample_rate) = Stream::new(pcm, config, Direction::Playback)?;
+        let resampler = playback_resampler(config.sample_rate, sample_rate, config.num_channels);
+        Ok(Player { stream: stream, sample_rate: sample_rate, resampler: resampler, fill: 0 } )
     }
-    
-    pub fn play(&self, data: Vec<i16>) {
-        let io = self.pcm.io_i16().unwrap();
+
+    // Makes wait_for_room() return as soon as no more than fill samples are queued in the device.
+    pub fn set_fill(&mut self, fill: u32) -> Result<(), AlsaError> {
+        self.fill = fill;
+        self.apply_fill()
+    }
+
+    // The device wakes up waiters once avail_min frames can be written, i.e. once the queued
+    // frames dropped to buffer size - avail_min.
+    fn apply_fill(&self) -> Result<(), AlsaError> {
+        if self.fill == 0 {
+            return Ok(());
+        }
+        let pcm = &self.stream.pcm;
+        let buffer_size = pcm.hw_params_current()?.get_buffer_size()?;
+        let channels = self.stream.num_channels as u64;
+        let mut fill = (self.fill as u64 / channels * self.sample_rate as u64 / self.stream.sample_rate as u64) as Frames;
+        if fill >= buffer_size {
+            warn!("playback device {} buffers only {} frames, keeping {} instead of {} queued", self.stream.devname, buffer_size, buffer_size / 2, fill);
+            fill = buffer_size / 2;
+        }
+        let swp = pcm.sw_params_current()?;
+        swp.set_avail_min(buffer_size - fill)?;
+        pcm.sw_params(&swp)?;
+        debug!("playback device {} keeps {} of {} frames queued", self.stream.devname, fill, buffer_size);
+        Ok(())
+    }
+
+    fn recover(&mut self, err: AlsaError) {
+        let sample_rate = self.stream.recover(err, self.sample_rate);
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.resampler = playback_resampler(self.stream.sample_rate, sample_rate, self.stream.num_channels);
+        }
+        // a reopened device starts with the default wakeup
+        if let Err(e) = self.apply_fill() {
+            warn!("cannot set playback fill of {}: {}", self.stream.devname, e);
+        }
+    }
+
+    // Blocks until the device has played down to the fill level, so that the next write keeps
+    // between fill and fill + one write queued.
+    pub fn wait_for_room(&mut self) {
+        match self.stream.pcm.wait(Some(WAIT_TIMEOUT_MS)) {
+            Ok(true) => {},
+            Ok(false) => debug!("playback device {} did not wake up within {} ms", self.stream.devname, WAIT_TIMEOUT_MS),
+            Err(err) => self.recover(err),
+        }
+    }
+
+    // samples (at the configured rate) written but not played yet
+    pub fn delay(&self) -> usize {
+        self.stream.queued(self.sample_rate)
+    }
+
+    // Blocks while the device is lost, the data is dropped if it cannot be written after recovering.
+    pub fn play(&mut self, data: Vec<i16>) {
+        let data = match self.resampler {
+            Some(ref mut resampler) => match resampler.process(&data) {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("cannot resample data for playback: {}", err);
+                    return;
+                }
+            },
+            None => data
+        };
         trace!("calling writei of len {}", data.len());
-        match io.writei(&data[..]) {
-            Ok(_) => {},
-            Err(err) => {
-                trace!("writei caused error: {}. Writing again.", err);
-                self.pcm.prepare().unwrap();
-                io.writei(&data[..]).unwrap();
+        let mut retried = false;
+        while let Err(err) = self.stream.write(&data[..]) {
+            trace!("writei caused error: {}", err);
+            let sample_rate = self.sample_rate;
+            self.recover(err);
+            // after a rate change the data was converted for the old rate
+            if retried || sample_rate != self.sample_rate {
+                return;
             }
+            retried = true;
         }
     }
 
-    pub fn spawn_play_thread(config: &AudioConfig, read_bucket_len: u32, buffer_mutex_play: sync::Arc<sync::Mutex<AudioBuffer>>) {
+    // The device's clock drives the thread: whenever it has played down to fill samples, the
+    // buckets queued meanwhile are stored and the next one is mixed and written, or silence if
+    // nothing is to be played, so the device never runs dry.
+    pub fn spawn_play_thread(config: &AudioConfig, pcm: PCM, fill: u32, mut mixer: Mixer) -> Result<(), Box<dyn std::error::Error>> {
         trace!("Spawning play thread");
-        let player = Player::new(config).unwrap();
-        let delay = 900.0 * (read_bucket_len as f32/ config.sample_rate as f32 );
-        let threshold = (1.5 * read_bucket_len as f32) as Frames;
-        trace!("delay {}, threshold {}", delay as u64, threshold);
-        let rbl = read_bucket_len;
-        let sr = config.sample_rate;
+        let mut player = Player::new(config, pcm)?;
+        player.set_fill(fill)?;
         thread::spawn(move || {
-            trace!("start");
-            let mut seq = 0;
             loop {
-                
-//*/                while player.get_remain() < threshold {
-//*/                    trace!("Player needs more data, thus calling get_next() and play()");
-//*/                    let data = match buffer_mutex_play.lock().unwrap().get_next(read_bucket_len) {
-//*/                        Some(data) => data,
-//*/                        None => {
-//*/                            trace!("Player returns no data, so do not play data");
-//*/                            break;
-//*/                        }
-//*/                    };
-//*/                    player.play(data);
-//*/                }
-//*/                // TODO: Adapt sleep time
-//*/                trace!("Ready to sleep");
-                
-
-                // Option2: Read as many buckets as available, wait an appropriate time:
-//                trace!("Remain: {}", player.get_remain());
-//                trace!("Reading data from ringbuffer");
-//                match buffer_mutex_play.lock().unwrap().get_next(read_bucket_len) {
-//                    Some(data) => {
-//                        seq = seq + 1;
-//                        trace!("Calling play / seq {}", seq);
-//                        player.play(data);
-//                        //std::thread::sleep(std::time::Duration::from_millis(50 as u64));
-//                        continue;
-//                    },
-//                    None => {
-//                        trace!("Player returns no data, so do not play data");
-//                    }
-//                };
-//                let local_delay = if seq > 0 {
-//                    900.0 * ((seq * rbl) as f32/ sr as f32 )
-//                }
-//                else {
-//                    50.0 * ((1 * rbl) as f32/ sr as f32 )
-//                };
-//                trace!("Sleeping {} after seq {}", local_delay, seq);
-//                std::thread::sleep(std::time::Duration::from_millis(local_delay as u64));
-//                seq = 0;
-//
-
-                // Option 3: Read only one bucket
-                trace!("Remain: {}", player.get_remain());
-                trace!("Reading data from ringbuffer");
-                match buffer_mutex_play.lock().unwrap().get_next(read_bucket_len) {
-                    Some(data) => {
-                        seq = seq + 1;
-                        trace!("Calling play / seq {}", seq);
-                        player.play(data);
+                player.wait_for_room();
+                let data = mixer.next_bucket();
+                match mixer.duplex {
+                    // the echo reference needs to know when the bucket will be heard
+                    Some(ref duplex) => {
+                        player.play(data.clone());
+                        duplex.lock().unwrap().note_played(&data, player.delay());
                     },
-                    None => {
-                        trace!("Player returns no data, so do not play data");
-                    }
-                };
-                let local_delay = if seq > 0 {
-                    200.0 * ((seq * rbl) as f32/ sr as f32 )
+                    None => player.play(data),
                 }
-                else {
-                    50.0 * ((1 * rbl) as f32/ sr as f32 )
-                };
-                trace!("Sleeping {} after seq {}", local_delay, seq);
-                std::thread::sleep(std::time::Duration::from_millis(local_delay as u64));
-                seq = 0;
-}
+            }
         });
+        Ok(())
     }
-    
+
 }
 
 // ========================================
 
-#[allow(dead_code)]
 pub struct Recorder {
-    pcm: PCM,
-    sample_rate: u32,  // currently not used
-    client_id: u16
+    stream: Stream,
+    sample_rate: u32,  // actual rate of the device, captured audio is resampled to format.sample_rate
+    format: StreamFormat,
+    client_id: u16,
+    latency: sync::Arc<AtomicUsize>,  // samples recorded after the last bucket handed to the callback
 }
 
 impl Recorder {
 
-    pub fn new(config: &AudioConfig, client_id: u16) -> Result<Recorder, Box<std::error::Error>> {
-        let cs = CString::new(config.devname)?;
-        let pcm = PCM::open(&*cs, Direction::Capture, false)?;
-        let sample_rate;
-        // The following block is needed to prevent borrow compile error because of hwp
-        {
-            let hwp = HwParams::any(&pcm)?;
-            hwp.set_channels(config.num_channels)?;
-            sample_rate = hwp.set_rate_near(config.sample_rate, ValueOr::Nearest)?;
-            if sample_rate != config.sample_rate {
-                trace!("Sample rate was changed from {} to {}", config.sample_rate, sample_rate);
-            }
-            hwp.set_format(Format::s16())?;
-            hwp.set_access(Access::RWInterleaved)?;
-            pcm.hw_params(&hwp)?;
+    // pcm is the device named in config, opened for capture by devices::select()
+    pub fn new(config: &AudioConfig, pcm: PCM, client_id: u16) -> Result<Recorder, Box<dyn std::error::Error>> {
+        let (stream, sample_rate) = Stream::new(pcm, config, Direction::Capture)?;
+        if sample_rate != config.sample_rate {
+            info!("Capture device does not support {} Hz, resampling from {} Hz", config.sample_rate, sample_rate);
         }
-        Ok(Recorder { pcm : pcm, sample_rate : sample_rate, client_id: client_id })
+        Ok(Recorder { stream: stream, sample_rate: sample_rate, format: config.format(), client_id: client_id, latency: sync::Arc::new(AtomicUsize::new(0)) })
     }
 
+    // updated before every call of the record callback, see echo::Duplex::take_reference
+    pub fn latency(&self) -> sync::Arc<AtomicUsize> {
+        self.latency.clone()
+    }
+
+    // Only returns if capturing cannot be started, later errors are recovered from and while the
+    // device is lost, nothing is recorded.
     // TODO: To allow mut code in closure, we have to declare F here as FnMut, not Fn. Is this OK?
-    pub fn record<F>(&self, write_bucket_len: u64, mut callback: F) -> Result<(), Box<std::error::Error>>
+    pub fn record<F>(&mut self, write_bucket_len: u64, mut callback: F) -> Result<(), Box<dyn std::error::Error>>
         where F : FnMut(AudioData) -> ()
     {
         trace!("record() start");
-        self.pcm.prepare()?;
-        let io = self.pcm.io_i16()?;
+        self.stream.pcm.prepare()?;
+        let channels = self.format.channels as u64;
         let mut i: u64 = 1;
+        let mut pending = Vec::with_capacity(2 * write_bucket_len as usize);
         loop {
-            let mut data = AudioData{ data: vec![0_i16; write_bucket_len as usize], pos: i, client_id: self.client_id };
-            io.readi(&mut data.data[..])?;
-            trace!("recorder with client_id {} calls callback for data at pos {} with len {}", self.client_id, i, write_bucket_len);
-            callback(data);
-            i = i + write_bucket_len;
+            let mut resampler = if self.sample_rate != self.format.sample_rate {
+                Some(Resampler::new(self.sample_rate, self.format.sample_rate, channels as usize))
+            } else {
+                None
+            };
+            let read_len = match resampler {
+                Some(_) => std::cmp::max(1, write_bucket_len / channels * self.sample_rate as u64 / self.format.sample_rate as u64) * channels,
+                None => write_bucket_len,
+            };
+            let mut buffer = vec![0_i16; read_len as usize];
+            let err = loop {
+                if let Err(err) = self.stream.read(&mut buffer[..]) {
+                    break err;
+                }
+                match resampler {
+                    Some(ref mut resampler) => pending.extend(resampler.process(&buffer)?),
+                    None => pending.extend_from_slice(&buffer),
+                }
+                let queued = self.stream.queued(self.sample_rate);
+                while pending.len() as u64 >= write_bucket_len {
+                    let rest = pending.split_off(write_bucket_len as usize);
+                    self.latency.store(queued + rest.len(), Ordering::Relaxed);
+                    let data = AudioData{ data: pending, pos: i, format: self.format, burst: Burst { id: 0, marker: BurstMarker::Continue }, priority: Priority::Normal, destination: None, client_id: self.client_id };
+                    trace!("recorder with client_id {} calls callback for data at pos {} with len {}", self.client_id, i, write_bucket_len);
+                    callback(data);
+                    i = i + write_bucket_len;
+                    pending = rest;
+                }
+            };
+            trace!("readi caused error: {}", err);
+            self.sample_rate = self.stream.recover(err, self.sample_rate);
         }
     }
-
-    pub fn spawn_record_thread(config: &AudioConfig, client_id: u16, write_bucket_len: u64, buffer_mutex_write: sync::Arc<sync::Mutex<AudioBuffer>>) {
-        trace!("Spawning record thread");
-        let recorder = Recorder::new(config, client_id).unwrap();
-        thread::spawn(move || {
-            recorder.record(write_bucket_len, |data| {
-                match buffer_mutex_write.lock().unwrap().store_data(data) {
-                    Ok(_) => {},
-                    Err(e) => {trace!("Could not store: {:?}", e)}
-                };
-            }).unwrap();
-        });
-    }
 }
@@ -0,0 +1,47 @@
+extern crate getopts;
+extern crate walkie_talkie_pi;
+
+use getopts::Options;
+use std::env;
+use std::process;
+use walkie_talkie_pi::config::Config;
+use walkie_talkie_pi::control::{self, Command};
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let mut opts = Options::new();
+    opts.optopt("s", "socket", "path of the control socket of the node", "PATH");
+    opts.optopt("c", "config", "take the socket path from the config file of the node", "FILE");
+    opts.optflag("h", "help", "print this help menu");
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|err| fail(&err.to_string()));
+    if matches.opt_present("h") || matches.free.is_empty() {
+        let brief = format!("Usage: {} [options] COMMAND\n\nCommands: status, channel NUM, mute, unmute, volume PERCENT, ptt on|off, peers, stats", args[0]);
+        println!("{}", opts.usage(&brief));
+        return;
+    }
+    let socket = match (matches.opt_str("s"), matches.opt_str("c")) {
+        (Some(path), _) => path,
+        (None, Some(path)) => Config::load(&path).unwrap_or_else(|err| fail(&err)).control.socket,
+        (None, None) => control::default_socket(),
+    };
+    // checked here as well, so typos do not need a running node to be noticed
+    let command = matches.free.join(" ");
+    if let Err(e) = Command::parse(&command) {
+        fail(&e);
+    }
+    match control::request(&socket, &command) {
+        Ok(lines) => for line in lines {
+            println!("{}", line);
+        },
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use audio::{AudioData, Bandwidth, Burst, BurstMarker, Priority, StreamFormat};
+use denoise::{HighPassFilter, NoiseSuppressor};
+use echo::EchoCanceller;
+use resample::Resampler;
+use std::time::{Duration, Instant};
+
+// Processing applied to every captured bucket before it is sent. All stages work on a single
+// channel, so interleaved buckets are split up and every channel gets its own filter state.
+pub struct CapturePipeline {
+    channels: usize,
+    sample_rate: u32,
+    echo_cancellers: Vec<EchoCanceller>,
+    high_pass_filters: Vec<HighPassFilter>,
+    noise_suppressors: Vec<NoiseSuppressor>,
+}
+
+impl CapturePipeline {
+    pub fn new(channels: u32, sample_rate: u32) -> CapturePipeline {
+        CapturePipeline {
+            channels: channels as usize,
+            sample_rate: sample_rate,
+            echo_cancellers: Vec::new(),
+            high_pass_filters: Vec::new(),
+            noise_suppressors: Vec::new(),
+        }
+    }
+
+    pub fn with_echo_cancellation(mut self, taps: usize) -> CapturePipeline {
+        self.echo_cancellers = (0..self.channels).map(|_| EchoCanceller::new(taps, 0.5)).collect();
+        self
+    }
+
+    pub fn with_high_pass(mut self, cutoff: f32) -> CapturePipeline {
+        let sample_rate = self.sample_rate;
+        self.high_pass_filters = (0..self.channels).map(|_| HighPassFilter::new(cutoff, sample_rate)).collect();
+        self
+    }
+
+    pub fn with_noise_suppression(mut self, strength: f32) -> CapturePipeline {
+        self.noise_suppressors = (0..self.channels).map(|_| NoiseSuppressor::new(strength)).collect();
+        self
+    }
+
+    // reference is the playback signal matching data, see echo::Duplex::take_reference
+    pub fn process(&mut self, data: &mut [i16], reference: &[i16]) {
+        if self.echo_cancellers.is_empty() && self.high_pass_filters.is_empty() && self.noise_suppressors.is_empty() {
+            return;
+        }
+        for channel in 0..self.channels {
+            let mut samples: Vec<i16> = data.iter().skip(channel).step_by(self.channels).cloned().collect();
+            if let Some(echo_canceller) = self.echo_cancellers.get_mut(channel) {
+                let reference: Vec<i16> = reference.iter().skip(channel).step_by(self.channels).cloned().collect();
+                echo_canceller.process(&mut samples, &reference);
+            }
+            if let Some(high_pass_filter) = self.high_pass_filters.get_mut(channel) {
+                high_pass_filter.process(&mut samples);
+            }
+            if let Some(noise_suppressor) = self.noise_suppressors.get_mut(channel) {
+                noise_suppressor.process(&mut samples);
+            }
+            for (pos, val) in samples.into_iter().enumerate() {
+                data[pos * self.channels + channel] = val;
+            }
+        }
+    }
+}
+
+// ========================================
+
+// Converts captured buckets to the format that is sent, i.e. resamples (and thereby band limits)
+// them to the rate of the selected bandwidth and renumbers their positions accordingly.
+pub struct WireEncoder {
+    format: StreamFormat,
+    priority: Priority,
+    destination: Option<u16>,
+    resampler: Resampler,
+    next_pos: u64,
+}
+
+impl WireEncoder {
+    pub fn new(capture: StreamFormat, bandwidth: Bandwidth, priority: Priority) -> WireEncoder {
+        let format = StreamFormat { sample_rate: bandwidth.sample_rate(capture.sample_rate), bandwidth: bandwidth, ..capture };
+        info!("sending {:?} bandwidth audio at {} Hz with {:?} priority", bandwidth, format.sample_rate, priority);
+        WireEncoder { format: format, priority: priority, destination: None, resampler: Resampler::new(capture.sample_rate, format.sample_rate, capture.channels as usize), next_pos: 1 }
+    }
+
+    // turns all transmissions into private calls to the given node
+    pub fn with_destination(mut self, node: u16) -> WireEncoder {
+        info!("calling node {} privately", node);
+        self.destination = Some(node);
+        self
+    }
+
+    pub fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    pub fn encode(&mut self, data: AudioData, burst: Burst) -> Result<AudioData, String> {
+        if burst.marker == BurstMarker::Start {
+            self.resampler.reset();
+            self.next_pos = 1;
+        }
+        let mut samples = self.resampler.process(&data.data)?;
+        if burst.marker == BurstMarker::End {
+            // the last samples of the transmission are only complete once the filter is fed silence
+            samples.extend(self.resampler.flush());
+        }
+        let pos = self.next_pos;
+        self.next_pos += samples.len() as u64;
+        Ok(AudioData { client_id: data.client_id, pos: pos, format: self.format, burst: burst, priority: self.priority, destination: self.destination, data: samples })
+    }
+}
+
+// ========================================
+
+// Decides which captured buckets are transmitted and frames them into bursts. Without voice
+// activation everything is sent as a single burst, with it a burst starts when the peak level
+// exceeds the threshold and ends after the level stayed below it for the hangover time. With
+// push-to-talk a burst lasts as long as the button is pressed, voice activation is ignored then.
+// While capture is muted, no burst starts and a running one ends, so that its end marker is still
+// sent.
+pub struct BurstFramer {
+    vox: Option<(i16, Duration)>,  // threshold and hangover
+    ptt: Option<bool>,  // whether the button is pressed, None without push-to-talk
+    next_id: u32,
+    active: Option<u32>,  // id of the running burst
+    last_voice: Instant,
+    muted: bool,
+}
+
+impl BurstFramer {
+    pub fn new(first_id: u32) -> BurstFramer {
+        BurstFramer { vox: None, ptt: None, next_id: first_id, active: None, last_voice: Instant::now(), muted: false }
+    }
+
+    pub fn with_vox(mut self, threshold: i16, hangover_ms: u64) -> BurstFramer {
+        self.vox = Some((threshold, Duration::from_millis(hangover_ms)));
+        self
+    }
+
+    pub fn with_push_to_talk(mut self) -> BurstFramer {
+        self.ptt = Some(false);
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    // set with every bucket, e.g. while remote audio is playing in listen duplex mode
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    // returns false without push-to-talk
+    pub fn set_pressed(&mut self, pressed: bool) -> bool {
+        match self.ptt {
+            Some(ref mut state) => {
+                *state = pressed;
+                true
+            },
+            None => false
+        }
+    }
+
+    fn start(&mut self) -> Option<Burst> {
+        if self.muted {
+            trace!("capture is muted, not starting a transmission");
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.active = Some(id);
+        info!("starting transmission {}", id);
+        Some(Burst { id: id, marker: BurstMarker::Start })
+    }
+
+    // returns None if the bucket is not to be sent
+    pub fn frame(&mut self, data: &[i16]) -> Option<Burst> {
+        if self.muted {
+            return self.active.take().map(|id| {
+                info!("ending transmission {}, capture is muted", id);
+                Burst { id: id, marker: BurstMarker::End }
+            });
+        }
+        if let Some(pressed) = self.ptt {
+            return match self.active {
+                None if pressed => self.start(),
+                None => None,
+                Some(id) if !pressed => {
+                    info!("ending transmission {}", id);
+                    self.active = None;
+                    Some(Burst { id: id, marker: BurstMarker::End })
+                },
+                Some(id) => Some(Burst { id: id, marker: BurstMarker::Continue })
+            };
+        }
+        let (threshold, hangover) = match self.vox {
+            Some(vox) => vox,
+            None => return match self.active {
+                Some(id) => Some(Burst { id: id, marker: BurstMarker::Continue }),
+                None => self.start()
+            }
+        };
+        let voice = data.iter().any(|val| val.saturating_abs() > threshold);
+        if voice {
+            self.last_voice = Instant::now();
+        }
+        match self.active {
+            None if voice => self.start(),
+            None => None,
+            Some(id) if self.last_voice.elapsed() > hangover => {
+                info!("ending transmission {}", id);
+                self.active = None;
+                Some(Burst { id: id, marker: BurstMarker::End })
+            },
+            Some(id) => Some(Burst { id: id, marker: BurstMarker::Continue })
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use audio::{Bandwidth, Priority, Tone};
+use control;
+use echo::DuplexMode;
+use mux::DEFAULT_CHANNEL;
+use packet_layer::{Transport, DEFAULT_PORT};
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use stats;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use toml;
+
+// All settings of a node. Every section and key is optional in the config file, missing ones
+// keep their defaults, unknown ones are rejected so typos do not go unnoticed.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub node: NodeConfig,
+    pub audio: AudioSettings,
+    pub buffer: BufferConfig,
+    pub network: NetworkConfig,
+    pub processing: ProcessingConfig,
+    pub tones: ToneConfig,
+    pub security: SecurityConfig,
+    pub control: ControlConfig,
+    pub display: DisplayConfig,
+    pub stats: StatsConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub callsign: Option<String>,  // defaults to "node <id>"
+    pub node_id: Option<u16>,      // random by default
+    pub channel: u16,              // only nodes on the same channel hear each other
+    #[serde(deserialize_with = "priority")]
+    pub priority: Priority,
+    pub call: Option<u16>,         // node to call privately
+    pub floor_control: bool,
+    pub push_to_talk: bool,        // only transmit while push-to-talk is pressed via the control socket
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig { callsign: None, node_id: None, channel: DEFAULT_CHANNEL, priority: Priority::Normal, call: None, floor_control: false, push_to_talk: false }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    pub device: String,  // used for capture and playback unless a separate device is given
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub fallback: bool,  // use another device if the configured one cannot be opened
+    pub sample_rate: u32,
+    pub channels: u32,
+    #[serde(deserialize_with = "bandwidth")]
+    pub bandwidth: Bandwidth,
+}
+
+impl AudioSettings {
+    pub fn input_device(&self) -> &str {
+        self.input_device.as_ref().unwrap_or(&self.device)
+    }
+
+    pub fn output_device(&self) -> &str {
+        self.output_device.as_ref().unwrap_or(&self.device)
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings { device: "default".to_string(), input_device: None, output_device: None, fallback: true, sample_rate: 44100, channels: 1, bandwidth: Bandwidth::Full }
+    }
+}
+
+// sizes are in samples
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    pub ring_buffer_size: u32,
+    pub read_bucket_size: u32,
+    pub write_bucket_size: u64,
+    pub spare_size: u64,
+    pub idle_threshold: u32,  // ms
+    pub playback_fill: u32,   // samples kept queued in the playback device
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig { ring_buffer_size: 1400 * 100, read_bucket_size: 4000, write_bucket_size: 1400, spare_size: 20000, idle_threshold: 500, playback_fill: 8000 }
+    }
+}
+
+// All nodes of a network have to run the same protocol version. Nodes sending version 2 still
+// understand version 1 nodes, but version 1 nodes drop every version 2 datagram, so audio, text
+// and floor requests of updated nodes are lost on them. Update all nodes together.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub port: u16,
+    pub bind_address: IpAddr,
+    pub broadcast_address: IpAddr,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        let transport = Transport::new(DEFAULT_PORT);
+        NetworkConfig { port: transport.port, bind_address: transport.bind_addr, broadcast_address: transport.broadcast_addr }
+    }
+}
+
+impl NetworkConfig {
+    pub fn transport(&self) -> Transport {
+        Transport { port: self.port, bind_addr: self.bind_address, broadcast_addr: self.broadcast_address }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessingConfig {
+    #[serde(deserialize_with = "duplex_mode")]
+    pub duplex: DuplexMode,
+    pub duplex_hangover: u64,  // ms
+    pub aec: bool,
+    pub aec_taps: usize,
+    pub high_pass: Option<f32>,  // cutoff in Hz
+    pub denoise: bool,
+    pub denoise_strength: f32,
+    pub vox: Option<i16>,      // threshold
+    pub vox_hangover: u64,     // ms
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> ProcessingConfig {
+        ProcessingConfig { duplex: DuplexMode::Full, duplex_hangover: 300, aec: false, aec_taps: 512, high_pass: None,
+            denoise: false, denoise_strength: 2.0, vox: None, vox_hangover: 800 }
+    }
+}
+
+// tones are given as "FREQ:MS[,FREQ:MS...]" or "off", like on the command line
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToneConfig {
+    #[serde(deserialize_with = "tones")]
+    pub roger_beep: Vec<Tone>,
+    #[serde(deserialize_with = "tones")]
+    pub busy_tone: Vec<Tone>,
+    #[serde(deserialize_with = "tones")]
+    pub call_tone: Vec<Tone>,
+}
+
+impl Default for ToneConfig {
+    fn default() -> ToneConfig {
+        ToneConfig {
+            roger_beep: Tone::parse_list("880:60,1320:90").unwrap(),
+            busy_tone: Tone::parse_list("480:200,0:200,480:200").unwrap(),
+            call_tone: Tone::parse_list("1200:100,0:100,1200:100,0:100,1200:100").unwrap(),
+        }
+    }
+}
+
+// Node ids are not authenticated, so these settings keep honest but misconfigured or noisy nodes
+// out, not attackers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub allowed_nodes: Vec<u16>,  // if not empty, only these nodes are heard
+    pub blocked_nodes: Vec<u16>,  // never heard
+    pub accept_emergency: bool,   // if false, emergency transmissions of others are treated as high priority
+}
+
+impl Default for SecurityConfig {
+    fn default() -> SecurityConfig {
+        SecurityConfig { allowed_nodes: Vec::new(), blocked_nodes: Vec::new(), accept_emergency: true }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    pub socket: String,  // path of the Unix domain socket
+}
+
+impl Default for ControlConfig {
+    fn default() -> ControlConfig {
+        ControlConfig { enabled: true, socket: control::default_socket() }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub tui: bool,  // show the live status display instead of plain output
+    pub refresh_ms: u64,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> DisplayConfig {
+        DisplayConfig { tui: false, refresh_ms: 200 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    pub log_interval: u64,  // seconds between logging the counters, 0 disables logging
+    #[serde(deserialize_with = "metrics_address")]
+    pub metrics_address: Option<SocketAddr>,  // serves the counters in Prometheus format if set, on 127.0.0.1 for a bare port
+}
+
+impl Default for StatsConfig {
+    fn default() -> StatsConfig {
+        StatsConfig { log_interval: 60, metrics_address: None }
+    }
+}
+
+fn parse_with<'de, D, T>(deserializer: D, parse: fn(&str) -> Result<T, String>) -> Result<T, D::Error>
+    where D: Deserializer<'de>
+{
+    let val = String::deserialize(deserializer)?;
+    parse(&val).map_err(D::Error::custom)
+}
+
+fn priority<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Priority, D::Error> {
+    parse_with(deserializer, Priority::parse)
+}
+
+fn bandwidth<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bandwidth, D::Error> {
+    parse_with(deserializer, Bandwidth::parse)
+}
+
+fn duplex_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DuplexMode, D::Error> {
+    parse_with(deserializer, DuplexMode::parse)
+}
+
+fn tones<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Tone>, D::Error> {
+    parse_with(deserializer, Tone::parse_list)
+}
+
+fn metrics_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SocketAddr>, D::Error> {
+    parse_with(deserializer, stats::parse_metrics_address).map(Some)
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let mut content = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| format!("cannot read config file {}: {}", path, err))?;
+        Config::parse(&content).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn parse(content: &str) -> Result<Config, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    // checks the settings that would otherwise only fail deep inside the audio code
+    pub fn validate(&self) -> Result<(), String> {
+        let audio = &self.audio;
+        let buffer = &self.buffer;
+        if audio.channels == 0 || audio.channels > 8 {
+            return Err(format!("audio.channels must be between 1 and 8, not {}", audio.channels));
+        }
+        if audio.sample_rate < 8000 || audio.sample_rate > 384000 {
+            return Err(format!("audio.sample_rate must be between 8000 and 384000 Hz, not {}", audio.sample_rate));
+        }
+        if buffer.write_bucket_size == 0 || buffer.write_bucket_size % audio.channels as u64 != 0 {
+            return Err(format!("buffer.write_bucket_size must be a positive multiple of audio.channels ({})", audio.channels));
+        }
+        if buffer.read_bucket_size == 0 || buffer.read_bucket_size % audio.channels != 0 {
+            return Err(format!("buffer.read_bucket_size must be a positive multiple of audio.channels ({})", audio.channels));
+        }
+        if buffer.spare_size + buffer.read_bucket_size as u64 >= buffer.ring_buffer_size as u64 {
+            return Err(format!("buffer.spare_size + buffer.read_bucket_size must be smaller than buffer.ring_buffer_size ({})", buffer.ring_buffer_size));
+        }
+        if buffer.playback_fill < buffer.read_bucket_size {
+            return Err(format!("buffer.playback_fill must be at least buffer.read_bucket_size ({})", buffer.read_bucket_size));
+        }
+        if buffer.write_bucket_size >= buffer.ring_buffer_size as u64 {
+            return Err(format!("buffer.write_bucket_size must be smaller than buffer.ring_buffer_size ({})", buffer.ring_buffer_size));
+        }
+        if self.network.port == 0 {
+            return Err("network.port must not be 0".to_string());
+        }
+        if let Some(ref callsign) = self.node.callsign {
+            if callsign.trim().is_empty() || callsign.chars().count() > 32 {
+                return Err(format!("node.callsign must have 1 to 32 characters, not '{}'", callsign));
+            }
+        }
+        let processing = &self.processing;
+        if let Some(cutoff) = processing.high_pass {
+            if cutoff.is_nan() || cutoff <= 0.0 || cutoff >= audio.sample_rate as f32 / 2.0 {
+                return Err(format!("processing.high_pass must be between 0 and {} Hz, not {}", audio.sample_rate / 2, cutoff));
+            }
+        }
+        if processing.vox.map_or(false, |threshold| threshold < 0) {
+            return Err("processing.vox must be between 0 and 32767".to_string());
+        }
+        if processing.aec_taps == 0 {
+            return Err("processing.aec_taps must be positive".to_string());
+        }
+        if processing.denoise_strength.is_nan() || processing.denoise_strength < 0.0 {
+            return Err(format!("processing.denoise_strength must not be negative, not {}", processing.denoise_strength));
+        }
+        if self.control.enabled && self.control.socket.is_empty() {
+            return Err("control.socket must not be empty".to_string());
+        }
+        if self.display.refresh_ms < 20 {
+            return Err(format!("display.refresh_ms must be at least 20, not {}", self.display.refresh_ms));
+        }
+        if self.node.push_to_talk && !self.control.enabled {
+            return Err("node.push_to_talk needs the control socket to be enabled".to_string());
+        }
+        Ok(())
+    }
+}
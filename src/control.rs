@@ -0,0 +1,218 @@
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{Error as IOError, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const SOCKET_NAME: &'static str = "walkie-talkie-pi.sock";
+// louder settings only amplify noise and clipping
+pub const MAX_VOLUME: u32 = 400;
+const MAX_LINE_LEN: usize = 256;
+
+// Line protocol of the control socket: the client sends one command per line, the node answers
+// with any number of data lines followed by a line that is either "ok" or "error: REASON".
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    Status,
+    Channel(u16),
+    Mute,
+    Unmute,
+    Volume(u32),  // in percent
+    PushToTalk(bool),
+    Peers,
+    Stats,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["status"] => Ok(Command::Status),
+            ["channel", channel] => channel.parse().map(Command::Channel).map_err(|err| format!("invalid channel '{}': {}", channel, err)),
+            ["mute"] => Ok(Command::Mute),
+            ["unmute"] => Ok(Command::Unmute),
+            ["volume", volume] => match volume.parse() {
+                Ok(volume) if volume <= MAX_VOLUME => Ok(Command::Volume(volume)),
+                Ok(volume) => Err(format!("volume {} is above the maximum of {}", volume, MAX_VOLUME)),
+                Err(err) => Err(format!("invalid volume '{}': {}", volume, err)),
+            },
+            ["ptt", "on"] => Ok(Command::PushToTalk(true)),
+            ["ptt", "off"] => Ok(Command::PushToTalk(false)),
+            ["peers"] => Ok(Command::Peers),
+            ["stats"] => Ok(Command::Stats),
+            _ => Err(format!("unknown command '{}' (expected status, channel NUM, mute, unmute, volume PERCENT, ptt on|off, peers or stats)", line.trim())),
+        }
+    }
+}
+
+fn own_uid() -> Result<u32, IOError> {
+    fs::metadata("/proc/self").map(|metadata| metadata.uid())
+}
+
+// used without XDG_RUNTIME_DIR, only accessible by the user running the node
+fn private_dir() -> PathBuf {
+    let user = own_uid().map(|uid| uid.to_string()).unwrap_or_else(|_| env::var("USER").unwrap_or_default());
+    env::temp_dir().join(format!("walkie-talkie-pi-{}", user))
+}
+
+// The socket is only accessible by the user running the node, as anybody able to connect could
+// control it: it is placed in the per-user runtime directory if there is one.
+pub fn default_socket() -> String {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => private_dir(),
+    };
+    dir.join(SOCKET_NAME).to_string_lossy().into_owned()
+}
+
+// creates the directory of the socket if it is missing, the private one has to stay private
+fn prepare_dir(path: &Path) -> Result<(), IOError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => return Ok(()),
+    };
+    if !dir.exists() {
+        return DirBuilder::new().mode(0o700).create(dir);
+    }
+    if dir == private_dir().as_path() {
+        let metadata = fs::metadata(dir)?;
+        if metadata.uid() != own_uid()? || metadata.mode() & 0o077 != 0 {
+            return Err(IOError::new(ErrorKind::PermissionDenied, format!("{} is not private to this user", dir.display())));
+        }
+    }
+    Ok(())
+}
+
+// The socket is created in a new directory only this user can enter and linked to path once it is
+// private, so it is never accessible by others, whatever the umask. Linking fails like bind does
+// when path is taken.
+fn bind_private(path: &Path) -> Result<UnixListener, IOError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let staging = dir.join(format!(".{}.{}", SOCKET_NAME, process::id()));
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(SOCKET_NAME);
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::hard_link(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    result
+}
+
+// Listens on a Unix domain socket and answers every command with the data lines returned by
+// handler. A socket file left behind by a node that is gone is replaced, one that is still in
+// use is not.
+pub fn serve<F>(path: &str, handler: F) -> Result<(), IOError>
+    where F: FnMut(Command) -> Result<Vec<String>, String> + Send + 'static
+{
+    prepare_dir(Path::new(path))?;
+    if UnixStream::connect(path).is_err() {
+        let _ = fs::remove_file(path);
+    }
+    let listener = bind_private(Path::new(path))?;
+    info!("control socket listening on {}", path);
+    let handler = Arc::new(Mutex::new(handler));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("cannot accept control connection: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &handler) {
+                    debug!("control connection closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection<F>(stream: UnixStream, handler: &Mutex<F>) -> Result<(), IOError>
+    where F: FnMut(Command) -> Result<Vec<String>, String>
+{
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut bytes = Vec::new();
+    loop {
+        bytes.clear();
+        if reader.by_ref().take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut bytes)? == 0 {
+            return Ok(());
+        }
+        if bytes.len() > MAX_LINE_LEN && bytes.last() != Some(&b'\n') {
+            // the rest must not be taken for another command
+            skip_line(&mut reader)?;
+            writeln!(writer, "error: line longer than {} bytes", MAX_LINE_LEN)?;
+            continue;
+        }
+        let line = String::from_utf8_lossy(&bytes);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = Command::parse(&line).and_then(|command| {
+            debug!("control command {:?}", command);
+            (*handler.lock().unwrap())(command)
+        });
+        match reply {
+            Ok(lines) => {
+                for data in lines {
+                    writeln!(writer, "{}", data)?;
+                }
+                writeln!(writer, "ok")?;
+            },
+            Err(e) => writeln!(writer, "error: {}", e)?,
+        }
+    }
+}
+
+fn skip_line<R: BufRead>(reader: &mut R) -> Result<(), IOError> {
+    loop {
+        let (found, len) = {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|byte| *byte == b'\n') {
+                Some(pos) => (true, pos + 1),
+                None => (false, buf.len()),
+            }
+        };
+        reader.consume(len);
+        if found {
+            return Ok(());
+        }
+    }
+}
+
+// Sends a single command to the node listening on path and returns its data lines.
+pub fn request(path: &str, command: &str) -> Result<Vec<String>, String> {
+    let stream = UnixStream::connect(path).map_err(|err| format!("cannot connect to {}: {}", path, err))?;
+    let mut writer = stream.try_clone().map_err(|err| err.to_string())?;
+    writeln!(writer, "{}", command).map_err(|err| format!("cannot send command: {}", err))?;
+    let mut lines = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|err| format!("cannot read reply: {}", err))?;
+        if line == "ok" {
+            return Ok(lines);
+        }
+        if let Some(reason) = line.strip_prefix("error: ") {
+            return Err(reason.to_string());
+        }
+        lines.push(line);
+    }
+    Err("connection closed before the reply was complete".to_string())
+}
@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+// ========================================
+
+// Second order Butterworth high pass (RBJ cookbook biquad), removes rumble from wind and engines.
+pub struct HighPassFilter {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl HighPassFilter {
+    pub fn new(cutoff: f32, sample_rate: u32) -> HighPassFilter {
+        assert!(cutoff > 0.0 && cutoff < sample_rate as f32 / 2.0);
+        let omega = 2.0 * PI * cutoff / sample_rate as f32;
+        let alpha = omega.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let a0 = 1.0 + alpha;
+        HighPassFilter {
+            b0: (1.0 + omega.cos()) / 2.0 / a0,
+            b1: -(1.0 + omega.cos()) / a0,
+            b2: (1.0 + omega.cos()) / 2.0 / a0,
+            a1: -2.0 * omega.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0, x2: 0.0, y1: 0.0, y2: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [i16]) {
+        for sample in data.iter_mut() {
+            let x = *sample as f32;
+            let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+            self.x2 = self.x1;
+            self.x1 = x;
+            self.y2 = self.y1;
+            self.y1 = y;
+            *sample = clamp(y);
+        }
+    }
+}
+
+fn clamp(val: f32) -> i16 {
+    val.max(i16::min_value() as f32).min(i16::max_value() as f32) as i16
+}
+
+// ========================================
+
+const FRAME_LEN: usize = 256;  // must be a power of two
+const HOP_LEN: usize = FRAME_LEN / 2;
+
+// Spectral subtraction noise suppressor. Works on overlapping frames of FRAME_LEN samples, tracks
+// the noise floor per frequency bin and attenuates bins that do not rise above it. The output is
+// delayed by FRAME_LEN samples, but always has the same length as the input.
+pub struct NoiseSuppressor {
+    strength: f32,      // over subtraction factor, higher values remove more noise (and more speech)
+    floor: f32,         // minimum gain per bin, prevents "musical noise"
+    window: Vec<f32>,   // sqrt hann, applied before analysis and after synthesis
+    noise: Vec<f32>,    // estimated noise power per bin
+    frames: u32,        // number of frames seen, the first ones initialize the noise estimate
+    input: VecDeque<f32>,
+    overlap: Vec<f32>,  // second half of the previous synthesized frame
+    output: VecDeque<i16>,
+}
+
+impl NoiseSuppressor {
+    pub fn new(strength: f32) -> NoiseSuppressor {
+        let window = (0..FRAME_LEN).map(|n| (0.5 - 0.5 * (2.0 * PI * n as f32 / FRAME_LEN as f32).cos()).sqrt()).collect();
+        NoiseSuppressor {
+            strength: strength,
+            floor: 0.1,
+            window: window,
+            noise: vec![0.0; FRAME_LEN / 2 + 1],
+            frames: 0,
+            input: VecDeque::with_capacity(2 * FRAME_LEN),
+            overlap: vec![0.0; HOP_LEN],
+            output: (0..FRAME_LEN).map(|_| 0).collect(),
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [i16]) {
+        self.input.extend(data.iter().map(|val| *val as f32));
+        while self.input.len() >= FRAME_LEN {
+            self.process_frame();
+            self.input.drain(..HOP_LEN);
+        }
+        for sample in data.iter_mut() {
+            *sample = self.output.pop_front().unwrap_or(0);
+        }
+    }
+
+    fn process_frame(&mut self) {
+        let mut re: Vec<f32> = self.input.iter().take(FRAME_LEN).zip(self.window.iter()).map(|(x, w)| x * w).collect();
+        let mut im = vec![0.0; FRAME_LEN];
+        fft(&mut re, &mut im, false);
+
+        self.frames += 1;
+        for bin in 0..FRAME_LEN / 2 + 1 {
+            let power = re[bin] * re[bin] + im[bin] * im[bin];
+            // the first frames are assumed to be noise only, afterwards the estimate follows bins
+            // that look like noise and only creeps up slowly in bins that look like speech
+            if self.frames <= 8 {
+                self.noise[bin] += power / 8.0;
+            } else if power < 4.0 * self.noise[bin] {
+                self.noise[bin] = 0.9 * self.noise[bin] + 0.1 * power;
+            } else {
+                self.noise[bin] *= 1.001;
+            }
+            let gain = if power > 0.0 {
+                (1.0 - self.strength * self.noise[bin] / power).max(self.floor * self.floor).sqrt()
+            } else {
+                self.floor
+            };
+            re[bin] *= gain;
+            im[bin] *= gain;
+            if bin > 0 && bin < FRAME_LEN / 2 {
+                re[FRAME_LEN - bin] *= gain;
+                im[FRAME_LEN - bin] *= gain;
+            }
+        }
+
+        fft(&mut re, &mut im, true);
+        for n in 0..HOP_LEN {
+            self.output.push_back(clamp(self.overlap[n] + re[n] * self.window[n]));
+            self.overlap[n] = re[n + HOP_LEN] * self.window[n + HOP_LEN];
+        }
+    }
+}
+
+// In place iterative radix 2 FFT, the inverse transform is scaled by 1/n.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+    if inverse {
+        for i in 0..n {
+            re[i] /= n as f32;
+            im[i] /= n as f32;
+        }
+    }
+}
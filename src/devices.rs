@@ -0,0 +1,187 @@
+use alsa::{Direction, ValueOr};
+use alsa::Error as AlsaError;
+use alsa::device_name::HintIter;
+use alsa::pcm::{PCM, HwParams, Format, Access};
+use std::ffi::CString;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// rates and channel counts devices are probed for
+const PROBED_RATES: [u32; 9] = [8000, 11025, 16000, 22050, 32000, 44100, 48000, 96000, 192000];
+const PROBED_CHANNELS: u32 = 8;
+// tried when the configured device cannot be opened
+const FALLBACK_DEVICE: &'static str = "default";
+// Linux error codes ALSA returns for failing streams
+const EIO: i32 = 5;
+const ENXIO: i32 = 6;
+const EAGAIN: i32 = 11;
+const ENODEV: i32 = 19;
+const EPIPE: i32 = 32;
+const EBADFD: i32 = 77;
+const ESTRPIPE: i32 = 86;
+
+// What a PCM device supports in one direction, in the sample format used everywhere (S16).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub rates: Vec<u32>,
+    pub channels: Vec<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub name: String,
+    pub description: Option<String>,
+    pub capture: Result<Capabilities, String>,   // Err if the device cannot capture or be opened
+    pub playback: Result<Capabilities, String>,
+}
+
+// Non-blocking, so a device that is in use does not stall the caller. Reads and writes do not
+// wait either then, see would_block().
+pub fn open(name: &str, direction: Direction) -> Result<PCM, String> {
+    let cs = CString::new(name).map_err(|err| err.to_string())?;
+    PCM::open(&cs, direction, true).map_err(|err| err.to_string())
+}
+
+fn supports<F>(pcm: &PCM, restrict: F) -> bool
+    where F: Fn(&HwParams) -> bool
+{
+    // every probe starts from the full configuration space
+    match HwParams::any(pcm) {
+        Ok(hwp) => hwp.set_format(Format::s16()).is_ok() && hwp.set_access(Access::RWInterleaved).is_ok() && restrict(&hwp),
+        Err(_) => false,
+    }
+}
+
+pub fn probe(name: &str, direction: Direction) -> Result<Capabilities, String> {
+    let pcm = open(name, direction)?;
+    let rates: Vec<u32> = PROBED_RATES.iter().cloned().filter(|rate| supports(&pcm, |hwp| hwp.set_rate(*rate, ValueOr::Nearest).is_ok())).collect();
+    let channels: Vec<u32> = (1..PROBED_CHANNELS + 1).filter(|channels| supports(&pcm, |hwp| hwp.set_channels(*channels).is_ok())).collect();
+    if rates.is_empty() || channels.is_empty() {
+        return Err("no 16 bit interleaved configuration".to_string());
+    }
+    Ok(Capabilities { rates: rates, channels: channels })
+}
+
+// All PCM devices the ALSA configuration knows of, with what they support in each direction.
+pub fn list() -> Result<Vec<Device>, String> {
+    let hints = HintIter::new(None, &CString::new("pcm").unwrap()).map_err(|err| format!("cannot enumerate devices: {}", err))?;
+    let mut devices = Vec::new();
+    for hint in hints {
+        let name = match hint.name {
+            Some(name) => name,
+            None => continue,
+        };
+        let unsupported = |direction| Err(format!("no {:?} device", direction));
+        let capture = if hint.direction != Some(Direction::Playback) { probe(&name, Direction::Capture) } else { unsupported(Direction::Capture) };
+        let playback = if hint.direction != Some(Direction::Capture) { probe(&name, Direction::Playback) } else { unsupported(Direction::Playback) };
+        devices.push(Device { name: name, description: hint.desc, capture: capture, playback: playback });
+    }
+    Ok(devices)
+}
+
+// Opens the configured device. Otherwise, with fallback enabled, the default device or else the
+// first device that can be opened in direction is opened. The device is returned open together
+// with its name, so nobody else can take it before it is used.
+pub fn select(configured: &str, direction: Direction, fallback: bool) -> Result<(String, PCM), String> {
+    let err = match open(configured, direction) {
+        Ok(pcm) => return Ok((configured.to_string(), pcm)),
+        Err(err) => format!("cannot open {:?} device {}: {}", direction, configured, err),
+    };
+    if !fallback {
+        return Err(err);
+    }
+    warn!("{}, looking for another device", err);
+    let mut candidates = vec![FALLBACK_DEVICE.to_string()];
+    if let Ok(hints) = HintIter::new(None, &CString::new("pcm").unwrap()) {
+        candidates.extend(hints.filter(|hint| hint.direction.is_none_or(|dir| dir == direction)).filter_map(|hint| hint.name));
+    }
+    for name in candidates {
+        if name == configured {
+            continue;
+        }
+        if let Ok(pcm) = open(&name, direction) {
+            warn!("using {:?} device {} instead of {}", direction, name, configured);
+            return Ok((name, pcm));
+        }
+    }
+    Err(format!("{} and no other device is available", err))
+}
+
+// What went wrong with a running stream, decides how it is recovered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    Xrun,       // buffer underrun (playback) or overrun (capture), the stream has to be prepared again
+    Suspended,  // the system was suspended, the stream has to be resumed
+    Lost,       // the device is gone, e.g. an unplugged USB sound card, and has to be reopened
+    Other,
+}
+
+// A read or write of a non-blocking stream found no data or no room, the stream is fine.
+pub fn would_block(err: &AlsaError) -> bool {
+    -err.code() == EAGAIN
+}
+
+pub fn classify(err: &AlsaError) -> Fault {
+    match -err.code() {
+        EPIPE => Fault::Xrun,
+        ESTRPIPE => Fault::Suspended,
+        ENODEV | ENXIO | EBADFD | EIO => Fault::Lost,
+        _ => Fault::Other,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Closed,   // not opened (yet)
+    Running,
+    Lost,     // being reopened
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            State::Closed => "closed",
+            State::Running => "running",
+            State::Lost => "lost, reopening",
+        })
+    }
+}
+
+// State of the capture or playback device of the process, updated by the recorder and player and
+// read by the status display and control socket.
+pub struct Health {
+    state: AtomicUsize,
+}
+
+impl Health {
+    const fn new() -> Health {
+        Health { state: AtomicUsize::new(0) }
+    }
+
+    pub fn state(&self) -> State {
+        match self.state.load(Ordering::Relaxed) {
+            1 => State::Running,
+            2 => State::Lost,
+            _ => State::Closed,
+        }
+    }
+
+    pub fn set(&self, state: State) {
+        let value = match state {
+            State::Closed => 0,
+            State::Running => 1,
+            State::Lost => 2,
+        };
+        self.state.store(value, Ordering::Relaxed);
+    }
+}
+
+pub static CAPTURE: Health = Health::new();
+pub static PLAYBACK: Health = Health::new();
+
+pub fn health(direction: Direction) -> &'static Health {
+    match direction {
+        Direction::Capture => &CAPTURE,
+        Direction::Playback => &PLAYBACK,
+    }
+}
@@ -0,0 +1,126 @@
+use audio::PeerStats;
+use devices::State;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_MESSAGES: usize = 5;
+const METER_WIDTH: usize = 30;
+// levels further below full scale are shown as an empty meter
+const METER_RANGE_DB: f32 = 60.0;
+
+// Where lines meant for the user go: straight to stdout, or into the status display, which would
+// overwrite them otherwise.
+#[derive(Clone)]
+pub struct Console {
+    messages: Option<Arc<Mutex<VecDeque<String>>>>,
+}
+
+impl Console {
+    pub fn plain() -> Console {
+        Console { messages: None }
+    }
+
+    pub fn for_display() -> Console {
+        Console { messages: Some(Arc::new(Mutex::new(VecDeque::with_capacity(MAX_MESSAGES)))) }
+    }
+
+    pub fn print(&self, line: String) {
+        match self.messages {
+            Some(ref messages) => {
+                let mut messages = messages.lock().unwrap();
+                if messages.len() == MAX_MESSAGES {
+                    messages.pop_front();
+                }
+                messages.push_back(line);
+            },
+            None => println!("{}", line),
+        }
+    }
+
+    fn messages(&self) -> Vec<String> {
+        self.messages.as_ref().map_or(Vec::new(), |messages| messages.lock().unwrap().iter().cloned().collect())
+    }
+}
+
+// Everything the status display shows, collected from the running node.
+pub struct Snapshot {
+    pub node: String,
+    pub channel: u16,
+    pub transmitting: bool,
+    pub talkers: Vec<String>,
+    pub input_level: i16,
+    pub output_level: i16,
+    pub muted: bool,
+    pub volume: u32,
+    pub peers: Vec<(String, PeerStats)>,
+    pub capture: State,
+    pub playback: State,
+}
+
+fn decibels(level: i16) -> f32 {
+    20.0 * (level.saturating_abs() as f32 / 32768.0).max(1e-6).log10()
+}
+
+// bar of the level in dB relative to full scale
+pub fn meter(level: i16) -> String {
+    let db = decibels(level);
+    let filled = (((db + METER_RANGE_DB) / METER_RANGE_DB).clamp(0.0, 1.0) * METER_WIDTH as f32).round() as usize;
+    let shown = if db <= -METER_RANGE_DB { "  -inf".to_string() } else { format!("{:6.1}", db) };
+    format!("[{}{}] {} dBFS", "#".repeat(filled), " ".repeat(METER_WIDTH - filled), shown)
+}
+
+pub fn render(snapshot: &Snapshot, messages: &[String], now: Instant) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!("{} on channel {}{}", snapshot.node, snapshot.channel, if snapshot.transmitting { "  >> TRANSMITTING <<" } else { "" }));
+    lines.push(format!("talking: {}", if snapshot.talkers.is_empty() { "-".to_string() } else { snapshot.talkers.join(", ") }));
+    lines.push(format!("in   {}", meter(snapshot.input_level)));
+    lines.push(format!("out  {}  volume {}%{}", meter(snapshot.output_level), snapshot.volume, if snapshot.muted { " (muted)" } else { "" }));
+    let buffered = snapshot.peers.iter().map(|(_, stats)| stats.buffered_ms).max().unwrap_or(0);
+    lines.push(format!("buffer: {} ms", buffered));
+    lines.push(format!("devices: capture {}, playback {}", snapshot.capture, snapshot.playback));
+    lines.push(String::new());
+    lines.push(format!("{:<24} {:>9} {:>8} {:>7} {:>10}", "peer", "level", "buffered", "loss", "heard"));
+    for (name, stats) in &snapshot.peers {
+        let total = stats.received + stats.lost;
+        let loss = if total == 0 { 0.0 } else { 100.0 * stats.lost as f32 / total as f32 };
+        let ago = now.duration_since(stats.last_heard);
+        let level = if decibels(stats.level) <= -METER_RANGE_DB { "-inf".to_string() } else { format!("{:.1}", decibels(stats.level)) };
+        lines.push(format!("{:<24} {:>5} dB {:>5} ms {:>6.1}% {:>8} s", name, level, stats.buffered_ms, loss, ago.as_secs()));
+    }
+    if !messages.is_empty() {
+        lines.push(String::new());
+        lines.extend(messages.iter().cloned());
+    }
+    lines
+}
+
+// Redraws the status display in place every interval_ms, snapshot is called for every frame.
+// Log output goes to stderr and should be redirected while the display is running.
+pub fn spawn<F>(console: Console, interval_ms: u64, mut snapshot: F)
+    where F: FnMut() -> Snapshot + Send + 'static
+{
+    thread::spawn(move || {
+        let stdout = io::stdout();
+        // clear the screen once, afterwards lines are overwritten to avoid flicker
+        print!("\x1b[2J");
+        loop {
+            let lines = render(&snapshot(), &console.messages(), Instant::now());
+            let mut out = stdout.lock();
+            let mut frame = String::from("\x1b[H");
+            for line in lines {
+                frame.push_str(&line);
+                frame.push_str("\x1b[K\n");
+            }
+            frame.push_str("\x1b[J");
+            if let Err(e) = out.write_all(frame.as_bytes()).and_then(|_| out.flush()) {
+                error!("cannot update status display: {}", e);
+                return;
+            }
+            drop(out);
+            thread::sleep(Duration::from_millis(interval_ms));
+        }
+    });
+}
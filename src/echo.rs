@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// peak amplitude above which a bucket counts as "somebody is talking"
+const ACTIVITY_THRESHOLD: i16 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DuplexMode {
+    Full,   // capture and playback run at the same time
+    Listen, // capture is muted while remote audio is playing
+    Talk,   // playback is muted while the local user is talking
+}
+
+impl DuplexMode {
+    pub fn parse(name: &str) -> Result<DuplexMode, String> {
+        match name {
+            "full" => Ok(DuplexMode::Full),
+            "listen" => Ok(DuplexMode::Listen),
+            "talk" => Ok(DuplexMode::Talk),
+            _ => Err(format!("unknown duplex mode '{}' (expected full, listen or talk)", name)),
+        }
+    }
+}
+
+fn peak(data: &[i16]) -> i16 {
+    data.iter().fold(0_i16, |max, val| std::cmp::max(max, val.saturating_abs()))
+}
+
+// ========================================
+
+// Shared between the play thread and the capture path: keeps the samples that were handed to the
+// player so the echo canceller can use them as reference, and tracks who is currently active.
+// Played samples are heard only once the device got through the audio queued before them, so the
+// reference is handed out delayed by what is queued in the playback device and the capture latency.
+pub struct Duplex {
+    mode: DuplexMode,
+    reference: VecDeque<i16>,
+    max_reference: usize,
+    samples_per_sec: u32,     // of the played and captured streams, all channels
+    playback_delay: usize,    // samples queued in the playback device after the last write
+    delay_measured: Instant,  // when playback_delay was measured
+    hangover: Duration,
+    last_remote: Option<Instant>,
+    last_local: Option<Instant>,
+    input_level: i16,   // peak of the last bucket captured
+    output_level: i16,  // peak of the last bucket played
+}
+
+impl Duplex {
+    // max_reference bounds the reference queue so player and recorder cannot drift apart
+    pub fn new(mode: DuplexMode, max_reference: usize, hangover_ms: u64, samples_per_sec: u32) -> Duplex {
+        Duplex {
+            mode: mode,
+            reference: VecDeque::with_capacity(max_reference),
+            max_reference: max_reference,
+            samples_per_sec: samples_per_sec,
+            playback_delay: 0,
+            delay_measured: Instant::now(),
+            hangover: Duration::from_millis(hangover_ms),
+            last_remote: None,
+            last_local: None,
+            input_level: 0,
+            output_level: 0,
+        }
+    }
+
+    // Called by the play thread with every bucket that is written to the device, silence included,
+    // so the reference does not slip. delay is the number of samples queued in the device after
+    // data was written.
+    pub fn note_played(&mut self, data: &[i16], delay: usize) {
+        self.playback_delay = delay;
+        self.delay_measured = Instant::now();
+        self.output_level = peak(data);
+        if self.output_level > ACTIVITY_THRESHOLD {
+            self.last_remote = Some(Instant::now());
+        }
+        self.reference.extend(data.iter());
+        while self.reference.len() > self.max_reference {
+            self.reference.pop_front();
+        }
+    }
+
+    // called by the capture path with every (echo cancelled) bucket
+    pub fn note_captured(&mut self, data: &[i16]) {
+        self.input_level = peak(data);
+        if self.input_level > ACTIVITY_THRESHOLD {
+            self.last_local = Some(Instant::now());
+        }
+    }
+
+    // Returns the playback reference matching a captured bucket of len samples, capture_latency is
+    // the number of samples recorded after the bucket that are still waiting in the capture device.
+    pub fn take_reference(&mut self, len: usize, capture_latency: usize) -> Vec<i16> {
+        let elapsed = self.delay_measured.elapsed();
+        self.take_reference_after(len, capture_latency, elapsed)
+    }
+
+    // like take_reference, with elapsed the time since the last note_played
+    pub fn take_reference_after(&mut self, len: usize, capture_latency: usize, elapsed: Duration) -> Vec<i16> {
+        // the newest reference sample is heard once the device played what is still queued, the
+        // bucket was heard capture_latency samples ago and spans len samples
+        let played = (elapsed.as_secs() * self.samples_per_sec as u64 + elapsed.subsec_nanos() as u64 * self.samples_per_sec as u64 / 1000000000) as usize;
+        let wanted = self.playback_delay.saturating_sub(played) + capture_latency + len;
+        while self.reference.len() > wanted {
+            self.reference.pop_front();
+        }
+        // nothing was played before the oldest reference sample
+        let silence = std::cmp::min(len, wanted - self.reference.len());
+        let mut result = vec![0_i16; silence];
+        result.extend(self.reference.drain(..len - silence));
+        trace!("took {} reference samples after {} of silence, {} left", len - silence, silence, self.reference.len());
+        result
+    }
+
+    fn is_active(&self, last: Option<Instant>) -> bool {
+        match last {
+            Some(instant) => instant.elapsed() < self.hangover,
+            None => false,
+        }
+    }
+
+    pub fn capture_muted(&self) -> bool {
+        self.mode == DuplexMode::Listen && self.is_active(self.last_remote)
+    }
+
+    pub fn playback_muted(&self) -> bool {
+        self.mode == DuplexMode::Talk && self.is_active(self.last_local)
+    }
+
+    pub fn input_level(&self) -> i16 {
+        self.input_level
+    }
+
+    pub fn output_level(&self) -> i16 {
+        self.output_level
+    }
+}
+
+// ========================================
+
+// Normalized least mean squares echo canceller: adaptively estimates the echo path from the
+// playback reference to the microphone and subtracts the estimated echo from the captured signal.
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    history: Vec<f32>,  // ring of the last weights.len() reference samples
+    head: usize,        // position of the newest sample in history
+    energy: f32,        // sum of squares of history
+    step: f32,
+}
+
+impl EchoCanceller {
+    pub fn new(taps: usize, step: f32) -> EchoCanceller {
+        assert!(taps > 0);
+        EchoCanceller { weights: vec![0.0; taps], history: vec![0.0; taps], head: 0, energy: 0.0, step: step }
+    }
+
+    // captured and reference must be aligned, i.e. reference[i] was played when captured[i] was recorded
+    pub fn process(&mut self, captured: &mut [i16], reference: &[i16]) {
+        assert_eq!(captured.len(), reference.len());
+        let taps = self.weights.len();
+        for (sample, far) in captured.iter_mut().zip(reference.iter()) {
+            let far = *far as f32;
+            self.head = (self.head + 1) % taps;
+            let oldest = self.history[self.head];
+            self.energy += far * far - oldest * oldest;
+            if self.energy < 0.0 {
+                self.energy = 0.0;
+            }
+            self.history[self.head] = far;
+
+            let mut estimate = 0.0;
+            let mut far_peak: f32 = 0.0;
+            for i in 0..taps {
+                let x = self.history[(self.head + taps - i) % taps];
+                estimate += self.weights[i] * x;
+                far_peak = far_peak.max(x.abs());
+            }
+            let near = *sample as f32;
+            let error = near - estimate;
+
+            // Geigel double talk detection: do not adapt while the local user talks over the echo
+            let double_talk = near.abs() > 0.5 * far_peak;
+            if !double_talk && self.energy > 0.0 {
+                let gain = self.step * error / (self.energy + 1.0);
+                for i in 0..taps {
+                    let x = self.history[(self.head + taps - i) % taps];
+                    self.weights[i] += gain * x;
+                }
+            }
+            *sample = error.max(i16::min_value() as f32).min(i16::max_value() as f32) as i16;
+        }
+    }
+}
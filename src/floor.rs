@@ -0,0 +1,170 @@
+use audio::Priority;
+use packet_layer::Prioritized;
+use mux::{MessageKind, KIND_FLOOR};
+use std::time::{Duration, Instant};
+
+// A request that took the floor at the requesting node was made without knowing of another
+// holder, so it is simultaneous with the holder's request, however long it was delayed. Such
+// conflicts are resolved the same way on every node, later requests wait until the floor is free.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum FloorMessage {
+    Request { node: u16, priority: Priority, burst: u32, granted: bool },
+    Release { node: u16, burst: u32 },
+}
+
+impl Prioritized for FloorMessage {
+    fn is_urgent(&self) -> bool {
+        match *self {
+            FloorMessage::Request { priority, .. } => priority == Priority::Emergency,
+            FloorMessage::Release { .. } => false,
+        }
+    }
+}
+
+impl MessageKind for FloorMessage {
+    const KIND: u8 = KIND_FLOOR;
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Holder {
+    node: u16,
+    priority: Priority,
+    burst: u32,
+    last_seen: Instant,
+}
+
+impl Holder {
+    fn new(node: u16, priority: Priority, burst: u32) -> Holder {
+        Holder { node: node, priority: priority, burst: burst, last_seen: Instant::now() }
+    }
+
+    // conflicts are resolved by priority first, then the lower node id wins
+    fn beats(&self, other: &Holder) -> bool {
+        (self.priority, other.node) > (other.priority, self.node)
+    }
+
+    // emergency requests take the floor over at any time, unless it is held by another emergency
+    fn preempts(&self, other: &Holder) -> bool {
+        self.priority == Priority::Emergency && other.priority < Priority::Emergency
+    }
+}
+
+// Keeps track of which node holds the floor, i.e. is allowed to talk. Every node applies the same
+// rules to the requests it sees, so all nodes agree on the holder without a central instance.
+pub struct FloorControl {
+    node: u16,
+    priority: Priority,
+    holder: Option<Holder>,
+    timeout: Duration,  // the floor is free again if the holder was not heard for this time
+}
+
+impl FloorControl {
+    pub fn new(node: u16, priority: Priority, timeout_ms: u64) -> FloorControl {
+        FloorControl { node: node, priority: priority, holder: None, timeout: Duration::from_millis(timeout_ms) }
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        if self.holder.map_or(false, |holder| holder.last_seen.elapsed() > timeout) {
+            info!("floor holder {} timed out", self.holder.unwrap().node);
+            self.holder = None;
+        }
+    }
+
+    // granted tells whether the requester took the floor in its own view
+    fn apply_request(&mut self, request: Holder, granted: bool) {
+        self.expire();
+        self.holder = match self.holder {
+            None => {
+                debug!("floor granted to {}", request.node);
+                Some(request)
+            },
+            Some(holder) if holder.node == request.node => Some(Holder { burst: request.burst, last_seen: Instant::now(), ..holder }),
+            Some(holder) if request.preempts(&holder) => {
+                warn!("floor pre-empted by emergency transmission of {} from {}", request.node, holder.node);
+                Some(request)
+            },
+            Some(holder) if granted && request.beats(&holder) => {
+                info!("floor taken over by {} from {}", request.node, holder.node);
+                Some(request)
+            },
+            Some(holder) => {
+                debug!("floor request of {} rejected, held by {}", request.node, holder.node);
+                Some(holder)
+            }
+        };
+    }
+
+    // the local user wants to talk, returns the message to broadcast
+    pub fn request(&mut self, burst: u32) -> FloorMessage {
+        let request = Holder::new(self.node, self.priority, burst);
+        // we know the current holder, so there is no conflict to resolve
+        self.apply_request(request, false);
+        let granted = self.holds_floor();
+        FloorMessage::Request { node: self.node, priority: self.priority, burst: burst, granted: granted }
+    }
+
+    // the local user stopped talking, returns the message to broadcast if we held the floor
+    pub fn release(&mut self, burst: u32) -> Option<FloorMessage> {
+        if self.holds_floor() {
+            self.holder = None;
+            Some(FloorMessage::Release { node: self.node, burst: burst })
+        } else {
+            None
+        }
+    }
+
+    pub fn handle(&mut self, message: FloorMessage) {
+        match message {
+            FloorMessage::Request { node, priority, burst, granted } => {
+                if node == self.node {
+                    return;
+                }
+                if !granted {
+                    // the requester knew of another holder, so it waits and does not talk
+                    debug!("floor request of {} ignored, it did not get the floor", node);
+                    return;
+                }
+                self.apply_request(Holder::new(node, priority, burst), true);
+            },
+            FloorMessage::Release { node, .. } => {
+                if self.holder.map_or(false, |holder| holder.node == node) {
+                    debug!("floor released by {}", node);
+                    self.holder = None;
+                }
+            }
+        }
+    }
+
+    // audio of a node was received, keeps its floor alive
+    pub fn note_heard(&mut self, node: u16) {
+        if let Some(ref mut holder) = self.holder {
+            if holder.node == node {
+                holder.last_seen = Instant::now();
+            }
+        }
+    }
+
+    pub fn holder(&mut self) -> Option<u16> {
+        self.expire();
+        self.holder.map(|holder| holder.node)
+    }
+
+    pub fn holds_floor(&mut self) -> bool {
+        let node = self.node;
+        if self.holder.map_or(false, |holder| holder.node == node) {
+            // we are talking, so we do not time out ourselves
+            self.note_heard(node);
+            return true;
+        }
+        false
+    }
+
+    // audio of node is only played while it holds the floor or the floor is free
+    pub fn may_play(&mut self, node: u16) -> bool {
+        match self.holder() {
+            Some(holder) => holder == node,
+            None => true,
+        }
+    }
+}
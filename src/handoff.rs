@@ -0,0 +1,63 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Bounded queue between exactly one producer and one consumer thread. Neither side ever waits for
+// the other: push fails when the queue is full and pop returns None when it is empty, so a slow
+// consumer cannot stall the producer.
+struct Shared<T> {
+    slots: Box<[UnsafeCell<Option<T>>]>,
+    head: AtomicUsize,  // items popped so far, only written by the consumer
+    tail: AtomicUsize,  // items pushed so far, only written by the producer
+}
+
+// a slot is only accessed by the side that currently owns it according to head and tail
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0);
+    let slots: Vec<UnsafeCell<Option<T>>> = (0..capacity).map(|_| UnsafeCell::new(None)).collect();
+    let shared = Arc::new(Shared { slots: slots.into_boxed_slice(), head: AtomicUsize::new(0), tail: AtomicUsize::new(0) });
+    (Producer { shared: shared.clone() }, Consumer { shared: shared })
+}
+
+impl<T> Producer<T> {
+    // hands the item back if the queue is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        let shared = &*self.shared;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        // acquire, so the consumer is done with the slot before it is reused
+        if tail.wrapping_sub(shared.head.load(Ordering::Acquire)) == shared.slots.len() {
+            return Err(item);
+        }
+        unsafe {
+            *shared.slots[tail % shared.slots.len()].get() = Some(item);
+        }
+        shared.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let shared = &*self.shared;
+        let head = shared.head.load(Ordering::Relaxed);
+        if head == shared.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*shared.slots[head % shared.slots.len()].get()).take() };
+        shared.head.store(head.wrapping_add(1), Ordering::Release);
+        item
+    }
+}
@@ -0,0 +1,26 @@
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate log;
+
+extern crate alsa;
+extern crate bincode;
+extern crate serde;
+extern crate rand;
+extern crate toml;
+
+pub mod packet_layer;
+pub mod audio;
+pub mod echo;
+pub mod denoise;
+pub mod capture;
+pub mod resample;
+pub mod floor;
+pub mod message;
+pub mod mux;
+pub mod config;
+pub mod control;
+pub mod display;
+pub mod stats;
+pub mod devices;
+pub mod handoff;
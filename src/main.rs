@@ -1,149 +1,665 @@
-#[macro_use] 
-extern crate serde_derive;
 #[macro_use]
 extern crate log;
 extern crate getopts;
 
-extern crate alsa;
-extern crate byteorder;
-extern crate bincode;
-extern crate serde;
 extern crate env_logger;
 extern crate rand;
+extern crate walkie_talkie_pi;
+extern crate alsa;
 
-mod packet_layer;
-mod audio;
-
-use packet_layer::packet_layer;
-use audio::RingBuffer;
+use walkie_talkie_pi::{audio, capture, echo, floor, message};
+use walkie_talkie_pi::config::Config;
+use walkie_talkie_pi::control::{self, Command};
+use walkie_talkie_pi::display::{self, Console, Snapshot};
+use walkie_talkie_pi::stats;
+use walkie_talkie_pi::devices;
+use walkie_talkie_pi::mux::Mux;
+use walkie_talkie_pi::packet_layer::Transport;
 use rand::Rng;
 use std::io;
 use std::io::BufRead;
 use std::thread;
-use std::collections::HashMap;
 use std::sync;
 use std::env;
-use std::io::Write;
-use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
-use getopts::Options;
+use getopts::{Matches, Options};
+use std::fmt;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use std::net::IpAddr;
+use alsa::Direction;
+use alsa::pcm::PCM;
 
-fn live(config: &audio::AudioConfig, ring_buf_len: u32, write_bucket_len: u64, read_bucket_len: u32, spare_len: u64, delay: u64, idle_threshold: u32) {
-    let buffer_mutex_play = sync::Arc::new(sync::Mutex::new(audio::AudioBuffer::new(ring_buf_len, spare_len, idle_threshold)));
-    let buffer_mutex_write = buffer_mutex_play.clone();
+// State of the running node the control socket reads and changes.
+struct NodeControl {
+    node_id: u16,
+    mux: sync::Arc<Mux>,
+    chat: sync::Arc<sync::Mutex<message::Chat>>,
+    playback: sync::Arc<audio::PlaybackControl>,
+    push_to_talk: Option<sync::Arc<AtomicBool>>,
+    transmitting: sync::Arc<AtomicBool>,
+}
 
-    audio::Recorder::spawn_record_thread(&config, 12345, write_bucket_len, buffer_mutex_write);
+impl NodeControl {
+    fn handle(&mut self, command: Command) -> Result<Vec<String>, String> {
+        match command {
+            Command::Status => {
+                let status = self.playback.status();
+                let chat = self.chat.lock().unwrap();
+                let talkers: Vec<String> = status.talkers.iter().map(|node| chat.name(*node)).collect();
+                Ok(vec![
+                    format!("node: {}", self.node_id),
+                    format!("channel: {}", self.mux.channel()),
+                    format!("transmitting: {}", self.transmitting.load(Ordering::SeqCst)),
+                    format!("push-to-talk: {}", match self.push_to_talk {
+                        Some(ref pressed) => if pressed.load(Ordering::SeqCst) { "pressed" } else { "released" },
+                        None => "off",
+                    }),
+                    format!("muted: {}", self.playback.user_muted()),
+                    format!("volume: {}", self.playback.volume()),
+                    format!("talking: {}", talkers.join(", ")),
+                    format!("capture device: {}", devices::CAPTURE.state()),
+                    format!("playback device: {}", devices::PLAYBACK.state()),
+                ])
+            },
+            Command::Channel(channel) => {
+                info!("switching to channel {}", channel);
+                self.mux.set_channel(channel);
+                Ok(Vec::new())
+            },
+            Command::Mute => {
+                self.playback.set_user_muted(true);
+                Ok(Vec::new())
+            },
+            Command::Unmute => {
+                self.playback.set_user_muted(false);
+                Ok(Vec::new())
+            },
+            Command::Volume(percent) => {
+                self.playback.set_volume(percent);
+                Ok(Vec::new())
+            },
+            Command::PushToTalk(pressed) => match self.push_to_talk {
+                Some(ref state) => {
+                    state.store(pressed, Ordering::SeqCst);
+                    Ok(Vec::new())
+                },
+                None => Err("push-to-talk is not enabled on this node".to_string()),
+            },
+            Command::Peers => {
+                let chat = self.chat.lock().unwrap();
+                let now = Instant::now();
+                Ok(self.mux.peers().iter().map(|peer| {
+                    let ago = now.duration_since(peer.last_heard);
+                    format!("{} channel {} last heard {}.{:03} s ago", chat.name(peer.node), peer.channel, ago.as_secs(), ago.subsec_nanos() / 1000000)
+                }).collect())
+            },
+            Command::Stats => Ok(stats::snapshot().iter().map(|&(name, value)| format!("{}: {}", name, value)).collect()),
+        }
+    }
+}
 
-    //std::thread::sleep(std::time::Duration::from_millis(delay));
-    
-    audio::Player::spawn_play_thread(&config, read_bucket_len, buffer_mutex_play);
-    
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(10000));
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(2);
+}
+
+// parses the value of an option, None if it was not given
+fn opt<T>(matches: &Matches, name: &str) -> Result<Option<T>, String>
+    where T: FromStr, T::Err: fmt::Display
+{
+    match matches.opt_str(name) {
+        Some(val) => val.parse().map(Some).map_err(|err| format!("invalid value '{}' for --{}: {}", val, name, err)),
+        None => Ok(None)
+    }
+}
+
+fn opt_with<T>(matches: &Matches, name: &str, parse: fn(&str) -> Result<T, String>) -> Result<Option<T>, String> {
+    match matches.opt_str(name) {
+        Some(val) => parse(&val).map(Some).map_err(|err| format!("invalid value '{}' for --{}: {}", val, name, err)),
+        None => Ok(None)
     }
 }
 
+// reads the config file, if one is given, and lets the command line options override it
+fn load_config(matches: &Matches) -> Result<Config, String> {
+    let mut config = match matches.opt_str("config") {
+        Some(path) => Config::load(&path)?,
+        None => Config::default()
+    };
+    {
+        let buffer = &mut config.buffer;
+        if let Some(val) = opt(matches, "ring-buffer-size")? { buffer.ring_buffer_size = val; }
+        if let Some(val) = opt(matches, "read-bucket-size")? { buffer.read_bucket_size = val; }
+        if let Some(val) = opt(matches, "write-bucket-size")? { buffer.write_bucket_size = val; }
+        if let Some(val) = opt(matches, "spare-size")? { buffer.spare_size = val; }
+        if let Some(val) = opt(matches, "idle-threshold")? { buffer.idle_threshold = val; }
+        if let Some(val) = opt(matches, "playback-fill")? { buffer.playback_fill = val; }
+    }
+    {
+        let audio = &mut config.audio;
+        if let Some(val) = matches.opt_str("audio-device") { audio.device = val; }
+        if let Some(val) = matches.opt_str("input-device") { audio.input_device = Some(val); }
+        if let Some(val) = matches.opt_str("output-device") { audio.output_device = Some(val); }
+        if matches.opt_present("no-device-fallback") { audio.fallback = false; }
+        if let Some(val) = opt(matches, "sample-rate")? { audio.sample_rate = val; }
+        if let Some(val) = opt(matches, "channels")? { audio.channels = val; }
+        if let Some(val) = opt_with(matches, "bandwidth", audio::Bandwidth::parse)? { audio.bandwidth = val; }
+    }
+    {
+        let network = &mut config.network;
+        if let Some(val) = opt(matches, "port")? { network.port = val; }
+        if let Some(val) = opt(matches, "bind-address")? { network.bind_address = val; }
+        if let Some(val) = opt(matches, "broadcast-address")? { network.broadcast_address = val; }
+    }
+    {
+        let node = &mut config.node;
+        if let Some(val) = matches.opt_str("callsign") { node.callsign = Some(val); }
+        if let Some(val) = opt(matches, "node-id")? { node.node_id = Some(val); }
+        if let Some(val) = opt(matches, "channel")? { node.channel = val; }
+        if let Some(val) = opt(matches, "call")? { node.call = Some(val); }
+        if let Some(val) = opt_with(matches, "priority", audio::Priority::parse)? { node.priority = val; }
+        if matches.opt_present("floor-control") { node.floor_control = true; }
+        if matches.opt_present("push-to-talk") { node.push_to_talk = true; }
+    }
+    {
+        let processing = &mut config.processing;
+        if let Some(val) = opt_with(matches, "duplex", echo::DuplexMode::parse)? { processing.duplex = val; }
+        if let Some(val) = opt(matches, "duplex-hangover")? { processing.duplex_hangover = val; }
+        if matches.opt_present("aec") { processing.aec = true; }
+        if let Some(val) = opt(matches, "aec-taps")? { processing.aec_taps = val; }
+        if let Some(val) = opt(matches, "high-pass")? { processing.high_pass = Some(val); }
+        if matches.opt_present("denoise") { processing.denoise = true; }
+        if let Some(val) = opt(matches, "denoise-strength")? { processing.denoise_strength = val; }
+        if let Some(val) = opt(matches, "vox")? { processing.vox = Some(val); }
+        if let Some(val) = opt(matches, "vox-hangover")? { processing.vox_hangover = val; }
+    }
+    {
+        let tones = &mut config.tones;
+        if let Some(val) = opt_with(matches, "roger-beep", audio::Tone::parse_list)? { tones.roger_beep = val; }
+        if let Some(val) = opt_with(matches, "busy-tone", audio::Tone::parse_list)? { tones.busy_tone = val; }
+        if let Some(val) = opt_with(matches, "call-tone", audio::Tone::parse_list)? { tones.call_tone = val; }
+    }
+    if let Some(val) = matches.opt_str("control-socket") { config.control.socket = val; }
+    if matches.opt_present("no-control-socket") { config.control.enabled = false; }
+    if matches.opt_present("tui") { config.display.tui = true; }
+    if let Some(val) = opt(matches, "stats-interval")? { config.stats.log_interval = val; }
+    if let Some(val) = opt_with(matches, "metrics-address", stats::parse_metrics_address)? { config.stats.metrics_address = Some(val); }
+    config.validate()?;
+    Ok(config)
+}
+
+const COMMANDS: &'static str = "Commands:
+    run           talk to other nodes (default)
+    loopback      play the captured audio locally through the buffer and mixer
+    net-loopback  like loopback, but through the packet layer on localhost
+    tone          play a test tone
+    list-devices  show the audio devices and what they support";
+
 fn main() {
     env_logger::init().unwrap();
-    let mut rng = rand::thread_rng();
-
 
     let args: Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
+    opts.optopt("c", "config", "read settings from this TOML file, options given here override it", "FILE");
     opts.optopt("b", "ring-buffer-size", "set ring-buffer size in bytes", "SIZE");
     opts.optopt("r", "read-bucket-size", "set bucket size in bytes for reads from ring buffer", "SIZE");
     opts.optopt("w", "write-bucket-size", "set bucket size in bytes for writes to ring buffer", "SIZE");
     opts.optopt("s", "spare-size", "set size in bytes of spare area in ring buffer", "SIZE");
-    opts.optopt("d", "delay", "set delay in ms", "DELAYMS");
-    opts.optopt("a", "audio-device", "set name of audio-device", "NAME");
+    opts.optopt("", "playback-fill", "set amount of audio in samples kept queued in the playback device", "SIZE");
+    opts.optopt("a", "audio-device", "set name of the audio device used for capture and playback, see list-devices", "NAME");
+    opts.optopt("", "input-device", "capture from this device instead of the audio device", "NAME");
+    opts.optopt("", "output-device", "play on this device instead of the audio device", "NAME");
+    opts.optflag("", "no-device-fallback", "fail instead of using another device if the configured one cannot be opened");
+    opts.optopt("", "sample-rate", "set sample rate in Hz used for capture and playback", "RATE");
+    opts.optopt("", "channels", "set number of channels used for capture and playback", "NUM");
+    opts.optopt("", "vox", "only transmit while the captured peak level exceeds this threshold (0-32767)", "LEVEL");
+    opts.optopt("", "vox-hangover", "time in ms a transmission continues after the level fell below the vox threshold", "TIME");
+    opts.optopt("", "roger-beep", "tones played when a transmission ends, FREQ:MS[,FREQ:MS...] or off", "TONES");
+    opts.optopt("", "busy-tone", "tones played when talking while the channel is busy, FREQ:MS[,FREQ:MS...] or off", "TONES");
+    opts.optopt("", "callsign", "name shown to other nodes next to text messages", "NAME");
+    opts.optopt("", "node-id", "identity of this node other nodes can call privately, random by default", "ID");
+    opts.optopt("", "call", "make a private call to the node with this id, only it plays the transmissions", "ID");
+    opts.optopt("", "call-tone", "tones played when a private call to this node starts, FREQ:MS[,FREQ:MS...] or off", "TONES");
+    opts.optflag("", "floor-control", "only let one node talk at a time, others get a busy tone");
+    opts.optopt("", "priority", "priority of transmissions: normal, high (relayed first) or emergency (pre-empts others, plays even when muted)", "PRIO");
+    opts.optopt("", "bandwidth", "voice bandwidth to send: narrow (8 kHz), wide (16 kHz) or full (sample rate)", "PRESET");
     opts.optopt("i", "idle-threshold", "threshold in ms to mark buffer as idle", "TIME");
+    opts.optopt("", "duplex", "full, listen (mute capture while remote audio plays) or talk (mute playback while talking)", "MODE");
+    opts.optopt("", "duplex-hangover", "time in ms a talker is still considered active after the last loud bucket", "TIME");
+    opts.optflag("", "aec", "enable acoustic echo cancellation of captured audio");
+    opts.optopt("", "aec-taps", "length in samples of the echo path modelled by the echo canceller", "TAPS");
+    opts.optopt("", "high-pass", "remove captured audio below this frequency in Hz", "FREQ");
+    opts.optflag("", "denoise", "enable noise suppression of captured audio");
+    opts.optopt("", "denoise-strength", "noise over-subtraction factor of the noise suppression", "FACTOR");
+    opts.optopt("", "port", "UDP port used to talk to other nodes", "PORT");
+    opts.optopt("", "bind-address", "local address to listen on", "ADDR");
+    opts.optopt("", "broadcast-address", "address advertisements are sent to", "ADDR");
+    opts.optopt("", "channel", "only hear and talk to nodes on this channel", "NUM");
+    opts.optflag("", "push-to-talk", "only transmit while push-to-talk is pressed via the control socket");
+    opts.optopt("", "control-socket", "path of the Unix domain socket walkie-ctl connects to, in $XDG_RUNTIME_DIR by default", "PATH");
+    opts.optflag("", "no-control-socket", "do not listen for control commands");
+    opts.optflag("", "tui", "show a live status display, log output should be redirected then");
+    opts.optopt("", "stats-interval", "log the statistics counters every SECS seconds, 0 disables it", "SECS");
+    opts.optopt("", "metrics-address", "serve the statistics counters in Prometheus format over HTTP on this port, on 127.0.0.1 unless an address is given; the endpoint is unauthenticated", "[ADDR:]PORT");
+    opts.optopt("", "test-tone", "tones the tone command repeats, FREQ:MS[,FREQ:MS...]", "TONES");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(e) => { panic!("Failed to parse Arguments") }
+        Err(e) => fail(&e.to_string())
     };
     if matches.opt_present("h") {
-        println!("{}", opts.usage(format!("{}: {}", &*args[0], "audio record and play").as_str()));
+        println!("{}\n\n{}", opts.usage(format!("{} [options] [command]: {}", &*args[0], "audio record and play").as_str()), COMMANDS);
         return;
     }
-    let ring_buf_len = match matches.opt_str("b") {
-        Some(val) => val.parse().unwrap_or_else(|err| panic!("could not parse '{}': {}", args[1], err)),
-        None => 1400 * 100
+    let command = match matches.free.len() {
+        0 => "run".to_string(),
+        1 => matches.free[0].clone(),
+        _ => fail(&format!("expected a single command, got '{}'", matches.free.join(" "))),
     };
-    let read_bucket_len = match matches.opt_str("r") {
-        Some(val) => val.parse().unwrap_or_else(|err| panic!("could not parse '{}': {}", args[1], err)),
-        None => 4000
-    };
-    let write_bucket_len = match matches.opt_str("w") {
-        Some(val) => val.parse().unwrap_or_else(|err| panic!("could not parse '{}': {}", args[1], err)),
-        None => 1400
-    };
-    let spare_len = match matches.opt_str("s") {
-        Some(val) => val.parse().unwrap_or_else(|err| panic!("could not parse '{}': {}", args[1], err)),
-        None => 20000
-    };
-    let idle_threshold = match matches.opt_str("i") {
-        Some(val) => val.parse().unwrap_or_else(|err| panic!("could not parse '{}': {}", args[1], err)),
-        None => 500
-    };
-    let delay = match matches.opt_str("d") {
-        Some(val) => val.parse().unwrap_or_else(|err| panic!("could not parse '{}': {}", args[1], err)),
-        None => 1000
-    };
-    let devname = match matches.opt_str("a") {
-        Some(val) => val,
-        None => "default".to_string()
+    let test_tone = opt_with(&matches, "test-tone", audio::Tone::parse_list).unwrap_or_else(|err| fail(&err))
+        .unwrap_or_else(|| audio::Tone::parse_list("1000:1000,0:500").unwrap());
+    let settings = load_config(&matches).unwrap_or_else(|err| fail(&err));
+
+    if settings.stats.log_interval > 0 {
+        stats::spawn_logger(settings.stats.log_interval);
+    }
+    if let Some(address) = settings.stats.metrics_address {
+        stats::serve_metrics(address).unwrap_or_else(|err| fail(&format!("cannot serve metrics on {}: {}", address, err)));
+    }
+
+    match command.as_str() {
+        "run" => run(&settings),
+        "loopback" => loopback(&settings),
+        "net-loopback" => net_loopback(&settings),
+        "tone" => tone(&settings, &test_tone),
+        "list-devices" => list_devices(),
+        _ => fail(&format!("unknown command '{}'\n\n{}", command, COMMANDS)),
+    }
+}
+
+fn audio_config<'a>(settings: &Config, device: &'a str) -> audio::AudioConfig<'a> {
+    audio::AudioConfig { devname: device, num_channels: settings.audio.channels, sample_rate: settings.audio.sample_rate }
+}
+
+// the name of the device and the device, opened in direction
+fn select_device(settings: &Config, direction: Direction) -> (String, PCM) {
+    let audio = &settings.audio;
+    let configured = match direction {
+        Direction::Capture => audio.input_device(),
+        Direction::Playback => audio.output_device(),
     };
+    devices::select(configured, direction, audio.fallback).unwrap_or_else(|err| fail(&err))
+}
 
-    //let config = audio::AudioConfig { devname: "plughw:Set", num_channels: 1, sample_rate: 44100 };
-    let config = audio::AudioConfig { devname: &devname, num_channels: 1, sample_rate: 44100 };
+fn list_devices() {
+    let devices = devices::list().unwrap_or_else(|err| fail(&err));
+    if devices.is_empty() {
+        println!("no audio devices found");
+    }
+    for device in devices {
+        println!("{}", device.name);
+        if let Some(description) = device.description {
+            for line in description.lines() {
+                println!("    {}", line);
+            }
+        }
+        for &(direction, ref capabilities) in &[("capture", &device.capture), ("playback", &device.playback)] {
+            match *capabilities {
+                Ok(ref caps) => {
+                    let rates: Vec<String> = caps.rates.iter().map(|rate| rate.to_string()).collect();
+                    let channels: Vec<String> = caps.channels.iter().map(|channels| channels.to_string()).collect();
+                    println!("    {}: rates {} Hz, channels {}", direction, rates.join(", "), channels.join(", "));
+                },
+                Err(ref e) => println!("    {}: unavailable ({})", direction, e),
+            }
+        }
+    }
+}
 
-    //live(&config, ring_buf_len, write_bucket_len, read_bucket_len, spare_len, delay, idle_threshold);
-        
-    let port = 1337;
-    let addr = rng.gen();
+fn new_audio_buffer(settings: &Config) -> audio::AudioBuffer {
+    let buffer = &settings.buffer;
+    audio::AudioBuffer::new(buffer.ring_buffer_size, buffer.spare_size, buffer.idle_threshold, audio_config(settings, &settings.audio.device).format())
+}
 
-    let (mut tx,rx) = packet_layer(port,addr).unwrap();
+// captured audio goes straight into the local mixer, so device and buffer settings can be checked
+// without a network
+fn loopback(settings: &Config) {
+    let ((input, input_pcm), (output, output_pcm)) = (select_device(settings, Direction::Capture), select_device(settings, Direction::Playback));
+    let (capture_config, config) = (audio_config(settings, &input), audio_config(settings, &output));
+    info!("loopback: playing captured audio from {} on {}", input, output);
+    let (mut queue, queued) = audio::play_queue();
+    let mixer = audio::Mixer::new(new_audio_buffer(settings), queued, settings.buffer.read_bucket_size);
+    audio::Player::spawn_play_thread(&config, output_pcm, settings.buffer.playback_fill, mixer)
+        .unwrap_or_else(|err| fail(&format!("cannot open playback device {}: {}", config.devname, err)));
+    let mut recorder = audio::Recorder::new(&capture_config, input_pcm, 1).unwrap_or_else(|err| fail(&format!("cannot open capture device {}: {}", input, err)));
+    recorder.record(settings.buffer.write_bucket_size, |data| audio::queue_for_playback(&mut queue, data))
+        .unwrap_or_else(|err| fail(&format!("capture failed: {}", err)));
+}
 
-    let buffer_mutex_play = sync::Arc::new(sync::Mutex::new(audio::AudioBuffer::new(ring_buf_len, spare_len, idle_threshold)));
-    let buffer_mutex_write = buffer_mutex_play.clone();
+// Two packet layers talk to each other over the loopback interface: the sending one on 127.0.0.1
+// and the receiving one on 127.0.0.2, both on the configured port. Besides the audio path this
+// exercises advertisements, send requests and the wire encoding.
+fn net_loopback(settings: &Config) {
+    let ((input, input_pcm), (output, output_pcm)) = (select_device(settings, Direction::Capture), select_device(settings, Direction::Playback));
+    let (capture_config, config) = (audio_config(settings, &input), audio_config(settings, &output));
+    let port = settings.network.port;
+    let local: IpAddr = "127.0.0.1".parse().unwrap();
+    let remote: IpAddr = "127.0.0.2".parse().unwrap();
+    let channel = settings.node.channel;
+    let sender = Mux::new(Transport { port: port, bind_addr: local, broadcast_addr: remote }, 1, 1, channel)
+        .unwrap_or_else(|err| fail(&format!("cannot open socket on {}:{}: {}", local, port, err)));
+    let receiver = Mux::new(Transport { port: port, bind_addr: remote, broadcast_addr: local }, 2, 2, channel)
+        .unwrap_or_else(|err| fail(&format!("cannot open socket on {}:{}: {}", remote, port, err)));
+    info!("net-loopback: sending from {}:{} to {}:{}", local, port, remote, port);
 
-    //audio::Recorder::spawn_record_thread(&config, 12345, buffer_mutex_write);
+    let tx = sender.sender::<audio::AudioData>();
+    let rx = receiver.subscribe::<audio::AudioData>().unwrap_or_else(|err| fail(&err));
+    let (mut queue, queued) = audio::play_queue();
+    thread::spawn(move || {
+        for data in rx.iter() {
+            audio::queue_for_playback(&mut queue, data);
+        }
+    });
+    let mixer = audio::Mixer::new(new_audio_buffer(settings), queued, settings.buffer.read_bucket_size);
+    audio::Player::spawn_play_thread(&config, output_pcm, settings.buffer.playback_fill, mixer)
+        .unwrap_or_else(|err| fail(&format!("cannot open playback device {}: {}", config.devname, err)));
+
+    let mut encoder = capture::WireEncoder::new(config.format(), settings.audio.bandwidth, settings.node.priority);
+    let mut framer = capture::BurstFramer::new(1);
+    let mut recorder = audio::Recorder::new(&capture_config, input_pcm, 1).unwrap_or_else(|err| fail(&format!("cannot open capture device {}: {}", input, err)));
+    recorder.record(settings.buffer.write_bucket_size, |data| {
+        if let Some(burst) = framer.frame(&data.data) {
+            match encoder.encode(data, burst) {
+                Ok(data) => tx.send(&data),
+                Err(err) => error!("cannot encode captured data: {}", err),
+            }
+        }
+    }).unwrap_or_else(|err| fail(&format!("capture failed: {}", err)));
+}
+
+// repeats the tones until the process is stopped
+fn tone(settings: &Config, tones: &[audio::Tone]) {
+    let (output, output_pcm) = select_device(settings, Direction::Playback);
+    let config = audio_config(settings, &output);
+    if tones.is_empty() {
+        fail("no test tone to play");
+    }
+    let mut player = audio::Player::new(&config, output_pcm).unwrap_or_else(|err| fail(&format!("cannot open playback device {}: {}", config.devname, err)));
+    player.set_fill(settings.buffer.playback_fill).unwrap_or_else(|err| fail(&format!("cannot configure playback device {}: {}", config.devname, err)));
+    let mut generator = audio::ToneGenerator::new(config.format());
+    info!("tone: playing {:?} on {}", tones, config.devname);
+    loop {
+        generator.queue(tones);
+        while generator.is_playing() {
+            let mut data = vec![0_i16; settings.buffer.read_bucket_size as usize];
+            generator.mix_into(&mut data);
+            player.wait_for_room();
+            player.play(data);
+        }
+    }
+}
 
-    //std::thread::sleep(std::time::Duration::from_millis(delay));
-    
-    //let mut audio_buffer = audio::AudioBuffer::new(config.buf_len);
-    
+fn run(settings: &Config) {
+    let mut rng = rand::thread_rng();
+    let ring_buf_len = settings.buffer.ring_buffer_size;
+    let read_bucket_len = settings.buffer.read_bucket_size;
+    let write_bucket_len = settings.buffer.write_bucket_size;
+    let spare_len = settings.buffer.spare_size;
+    let idle_threshold = settings.buffer.idle_threshold;
+    let processing = settings.processing.clone();
+    let node = settings.node.clone();
+
+    //let config = audio::AudioConfig { devname: "plughw:Set", num_channels: 1, sample_rate: 44100 };
+    let ((input, input_pcm), (output, output_pcm)) = (select_device(settings, Direction::Capture), select_device(settings, Direction::Playback));
+    let (capture_config, config) = (audio_config(settings, &input), audio_config(settings, &output));
+
+    let addr = rng.gen();
+    let node_id: u16 = node.node_id.unwrap_or_else(|| rng.gen());
+    info!("node id is {}", node_id);
+    let callsign = node.callsign.clone().unwrap_or(format!("node {}", node_id));
+
+    let mux = sync::Arc::new(Mux::new(settings.network.transport(), addr, node_id, node.channel)
+        .unwrap_or_else(|err| fail(&format!("cannot open socket on port {}: {}", settings.network.port, err))));
+    mux.set_node_filter(settings.security.allowed_nodes.clone(), settings.security.blocked_nodes.clone());
+    info!("listening on channel {}", node.channel);
+    let tx = mux.sender::<audio::AudioData>();
+    let rx = mux.subscribe::<audio::AudioData>().unwrap_or_else(|err| fail(&err));
+
+    let console = if settings.display.tui { Console::for_display() } else { Console::plain() };
+    let console_text = console.clone();
+    let console_ack = console.clone();
+    let console_input = console.clone();
+    let chat = sync::Arc::new(sync::Mutex::new(message::Chat::new(node_id, callsign.clone(), rng.gen())));
+    let chat_text = chat.clone();
+    let chat_ack = chat.clone();
+    let text_tx = mux.sender::<message::TextMessage>();
+    let text_rx = mux.subscribe::<message::TextMessage>().unwrap_or_else(|err| fail(&err));
+    let ack_tx = mux.sender::<message::TextAck>();
+    let ack_rx = mux.subscribe::<message::TextAck>().unwrap_or_else(|err| fail(&err));
+    thread::spawn(move || {
+        for text in text_rx.iter() {
+            if let Some((line, ack)) = chat_text.lock().unwrap().receive(&text) {
+                console_text.print(line);
+                if let Some(ack) = ack {
+                    ack_tx.send(&ack);
+                }
+            }
+        }
+    });
     thread::spawn(move || {
-    	loop {
-    	    let (data, _, _) = rx.receive().unwrap();
-	    buffer_mutex_write.lock().unwrap().store_data(data);
-    	}
+        for ack in ack_rx.iter() {
+            if let Some(line) = chat_ack.lock().unwrap().receive_ack(&ack) {
+                console_ack.print(line);
+            }
+        }
     });
 
-    audio::Player::spawn_play_thread(&config, read_bucket_len, buffer_mutex_play);
-    //thread::spawn(move || {
-    let recorder = audio::Recorder::new(&config, rng.gen()).unwrap();
-    recorder.record(write_bucket_len, |data| {tx.send(data); });
-    //});
-    //loop{
-    //    std::thread::sleep(std::time::Duration::from_millis(20000));
-    //}
-
-    //std::thread::sleep(std::time::Duration::from_millis(delay));
-	
-    /*thread::spawn(move || {
-        loop {
-            let (payload, address, sequence_number) = rx.receive().unwrap();
-            println!("{},{}: {}",address, sequence_number, String::from_utf8(payload).unwrap());
+    let mut floor_control = if node.floor_control {
+        let floor = sync::Arc::new(sync::Mutex::new(floor::FloorControl::new(node_id, node.priority, 2000)));
+        let floor_tx = mux.sender::<floor::FloorMessage>();
+        let floor_rx = mux.subscribe::<floor::FloorMessage>().unwrap_or_else(|err| fail(&err));
+        let floor_receive = floor.clone();
+        thread::spawn(move || {
+            for message in floor_rx.iter() {
+                floor_receive.lock().unwrap().handle(message);
+            }
+        });
+        Some((floor, floor_tx))
+    } else {
+        None
+    };
+    let floor_play = floor_control.as_ref().map(|(floor, _)| floor.clone());
+
+    let mut audio_buffer = audio::AudioBuffer::new(ring_buf_len, spare_len, idle_threshold, config.format());
+    audio_buffer.set_roger_beep(settings.tones.roger_beep.clone());
+    audio_buffer.set_busy_tone(settings.tones.busy_tone.clone());
+    audio_buffer.set_call_tone(settings.tones.call_tone.clone());
+    audio_buffer.set_node_id(node_id);
+    // the network thread only hands received audio over, storing and mixing is up to the player
+    let (mut play_queue, play_queued) = audio::play_queue();
+
+    let accept_emergency = settings.security.accept_emergency;
+    thread::spawn(move || {
+        while let Ok(mut data) = rx.recv() {
+            if !accept_emergency && data.priority == audio::Priority::Emergency {
+                data.priority = audio::Priority::High;
+            }
+            if let Some(ref floor) = floor_play {
+                let mut floor = floor.lock().unwrap();
+                if data.priority < audio::Priority::Emergency && !floor.may_play(data.client_id) {
+                    trace!("client {} does not hold the floor, dropping data at pos {}", data.client_id, data.pos);
+                    continue;
+                }
+                floor.note_heard(data.client_id);
+            }
+            audio::queue_for_playback(&mut play_queue, data);
         }
+        info!("packet layer is gone, no more audio is received");
     });
 
-    loop {
+    // the reference queue holds at most the ring buffer's worth of played samples
+    let duplex = sync::Arc::new(sync::Mutex::new(echo::Duplex::new(processing.duplex, ring_buf_len as usize, processing.duplex_hangover, config.sample_rate * config.num_channels)));
+    let mixer = audio::Mixer::new(audio_buffer, play_queued, read_bucket_len).with_duplex(duplex.clone());
+    let playback = mixer.control();
+    let mut pipeline = capture::CapturePipeline::new(config.num_channels, config.sample_rate);
+    if processing.aec {
+        pipeline = pipeline.with_echo_cancellation(processing.aec_taps);
+    }
+    if let Some(cutoff) = processing.high_pass {
+        pipeline = pipeline.with_high_pass(cutoff);
+    }
+    if processing.denoise {
+        pipeline = pipeline.with_noise_suppression(processing.denoise_strength);
+    }
+
+    let mut encoder = capture::WireEncoder::new(config.format(), settings.audio.bandwidth, node.priority);
+    if let Some(node) = node.call {
+        encoder = encoder.with_destination(node);
+    }
+    let mut framer = capture::BurstFramer::new(rng.gen());
+    if let Some(threshold) = processing.vox {
+        framer = framer.with_vox(threshold, processing.vox_hangover);
+    }
+    let push_to_talk = if node.push_to_talk {
+        framer = framer.with_push_to_talk();
+        Some(sync::Arc::new(AtomicBool::new(false)))
+    } else {
+        None
+    };
+    let push_to_talk_capture = push_to_talk.clone();
+    let transmitting = sync::Arc::new(AtomicBool::new(false));
+    let transmitting_capture = transmitting.clone();
+
+    if settings.display.tui {
+        let mux = mux.clone();
+        let chat = chat.clone();
+        let playback = playback.clone();
+        let duplex = duplex.clone();
+        let transmitting = transmitting.clone();
+        let name = format!("{} ({})", callsign, node_id);
+        display::spawn(console, settings.display.refresh_ms, move || {
+            let (input_level, output_level) = {
+                let duplex = duplex.lock().unwrap();
+                (duplex.input_level(), duplex.output_level())
+            };
+            let status = playback.status();
+            let chat = chat.lock().unwrap();
+            Snapshot {
+                node: name.clone(),
+                channel: mux.channel(),
+                transmitting: transmitting.load(Ordering::SeqCst),
+                talkers: status.talkers.iter().map(|node| chat.name(*node)).collect(),
+                input_level: input_level,
+                output_level: output_level,
+                muted: playback.user_muted(),
+                volume: playback.volume(),
+                peers: status.peers.into_iter().map(|stats| (chat.name(stats.client_id), stats)).collect(),
+                capture: devices::CAPTURE.state(),
+                playback: devices::PLAYBACK.state(),
+            }
+        });
+    }
+
+    if settings.control.enabled {
+        let mut node_control = NodeControl {
+            node_id: node_id,
+            mux: mux.clone(),
+            chat: chat.clone(),
+            playback: playback.clone(),
+            push_to_talk: push_to_talk,
+            transmitting: transmitting,
+        };
+        control::serve(&settings.control.socket, move |command| node_control.handle(command))
+            .unwrap_or_else(|err| fail(&format!("cannot listen on control socket {}: {}", settings.control.socket, err)));
+    }
+
+    audio::Player::spawn_play_thread(&config, output_pcm, settings.buffer.playback_fill, mixer)
+        .unwrap_or_else(|err| fail(&format!("cannot open playback device {}: {}", config.devname, err)));
+
+    // every line typed on stdin is sent as text message, lines starting with @ID only to that node
+    thread::spawn(move || {
         let stdin = io::stdin();
         for line in stdin.lock().lines() {
-            tx.send(line.unwrap().into_bytes());
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("cannot read from stdin: {}", e);
+                    break;
+                }
+            };
+            match chat.lock().unwrap().compose(&line) {
+                Ok(Some(text)) => text_tx.send(&text),
+                Ok(None) => {},
+                Err(e) => console_input.print(e),
+            }
         }
-    }*/
-
+    });
+    let mut recorder = audio::Recorder::new(&capture_config, input_pcm, node_id)
+        .unwrap_or_else(|err| fail(&format!("cannot open capture device {}: {}", input, err)));
+    let capture_latency = recorder.latency();
+    let mut silenced_burst = None;
+    recorder.record(write_bucket_len, |mut data| {
+        let reference = duplex.lock().unwrap().take_reference(data.data.len(), capture_latency.load(Ordering::Relaxed));
+        pipeline.process(&mut data.data, &reference);
+        // the player needs the duplex state for every bucket, so it is not kept locked while sending
+        let capture_muted = {
+            let mut duplex = duplex.lock().unwrap();
+            duplex.note_captured(&data.data);
+            duplex.capture_muted()
+        };
+        if let Some(ref pressed) = push_to_talk_capture {
+            framer.set_pressed(pressed.load(Ordering::SeqCst));
+        }
+        // a burst is held back while muted, so the floor is only requested for bursts that are
+        // sent, and a running one is ended, its end marker goes out without the muted audio
+        framer.set_muted(capture_muted);
+        let burst = framer.frame(&data.data);
+        transmitting_capture.store(framer.is_active(), Ordering::SeqCst);
+        let burst = match burst {
+            Some(burst) => burst,
+            None => return
+        };
+        if capture_muted {
+            trace!("remote audio is playing, not sending captured data of transmission {}", burst.id);
+            data.data.clear();
+        }
+        if let Some((ref floor, ref mut floor_tx)) = floor_control {
+            let mut floor = floor.lock().unwrap();
+            if burst.marker == audio::BurstMarker::Start {
+                floor_tx.send(&floor.request(burst.id));
+            }
+            let holds_floor = floor.holds_floor();
+            if burst.marker == audio::BurstMarker::End {
+                if let Some(message) = floor.release(burst.id) {
+                    floor_tx.send(&message);
+                }
+            }
+            // a burst without the floor stays silent until its end, even if the floor becomes
+            // free in between, as it would continue without a start otherwise
+            if silenced_burst == Some(burst.id) {
+                return;
+            }
+            if !holds_floor {
+                info!("floor is held by {:?}, not transmitting", floor.holder());
+                playback.play_busy_tone();
+                silenced_burst = Some(burst.id);
+                return;
+            }
+        } else if burst.marker == audio::BurstMarker::Start {
+            playback.check_busy();
+        }
+        let data = match encoder.encode(data, burst) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("cannot encode captured data: {}", err);
+                return;
+            }
+        };
+        if !data.data.is_empty() || burst.marker != audio::BurstMarker::Continue {
+            tx.send(&data);
+        }
+    }).unwrap_or_else(|err| fail(&format!("capture failed: {}", err)));
 }
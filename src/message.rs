@@ -0,0 +1,102 @@
+use mux::{MessageKind, KIND_TEXT, KIND_TEXT_ACK};
+use packet_layer::Prioritized;
+use std::collections::HashMap;
+
+// longer lines are cut off, so a text message always fits into a single datagram
+pub const MAX_TEXT_LEN: usize = 1000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextMessage {
+    pub sender: u16,
+    pub callsign: String,
+    pub id: u32,
+    pub destination: Option<u16>,  // node a private message is addressed to, None for everybody
+    pub text: String,
+}
+
+// sent back by the addressee of a private message once it was displayed, messages to everybody
+// are not acknowledged, as every node would answer them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextAck {
+    pub sender: u16,
+    pub callsign: String,
+    pub message: u32,
+    pub to: u16,
+}
+
+impl Prioritized for TextMessage {}
+
+impl MessageKind for TextMessage {
+    const KIND: u8 = KIND_TEXT;
+}
+
+impl Prioritized for TextAck {}
+
+impl MessageKind for TextAck {
+    const KIND: u8 = KIND_TEXT_ACK;
+}
+
+// Composes outgoing text messages and keeps track of the names of the nodes that were heard.
+pub struct Chat {
+    node_id: u16,
+    callsign: String,
+    next_id: u32,
+    names: HashMap<u16, String>,
+}
+
+impl Chat {
+    pub fn new(node_id: u16, callsign: String, first_id: u32) -> Chat {
+        Chat { node_id: node_id, callsign: callsign, next_id: first_id, names: HashMap::new() }
+    }
+
+    pub fn name(&self, node: u16) -> String {
+        match self.names.get(&node) {
+            Some(name) => format!("{} ({})", name, node),
+            None => format!("node {}", node),
+        }
+    }
+
+    // a line starting with @ID is sent to that node only
+    pub fn compose(&mut self, line: &str) -> Result<Option<TextMessage>, String> {
+        let line = line.trim();
+        let (destination, text) = if let Some(addressed) = line.strip_prefix('@') {
+            let mut parts = addressed.splitn(2, ' ');
+            let node = parts.next().unwrap_or("");
+            let node = node.parse::<u16>().map_err(|err| format!("invalid node id '{}': {}", node, err))?;
+            (Some(node), parts.next().unwrap_or("").trim())
+        } else {
+            (None, line)
+        };
+        if text.is_empty() {
+            return Ok(None);
+        }
+        let text: String = text.chars().take(MAX_TEXT_LEN).collect();
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(Some(TextMessage { sender: self.node_id, callsign: self.callsign.clone(), id: id, destination: destination, text: text }))
+    }
+
+    // returns the line to display and the acknowledgement to send, or None if the message is not for us
+    pub fn receive(&mut self, message: &TextMessage) -> Option<(String, Option<TextAck>)> {
+        if message.sender == self.node_id || message.destination.map_or(false, |node| node != self.node_id) {
+            return None;
+        }
+        self.names.insert(message.sender, message.callsign.clone());
+        Some(match message.destination {
+            Some(_) => {
+                let ack = TextAck { sender: self.node_id, callsign: self.callsign.clone(), message: message.id, to: message.sender };
+                (format!("[private] <{}> {}", self.name(message.sender), message.text), Some(ack))
+            },
+            None => (format!("<{}> {}", self.name(message.sender), message.text), None),
+        })
+    }
+
+    // returns the line to display if the acknowledged message was ours
+    pub fn receive_ack(&mut self, ack: &TextAck) -> Option<String> {
+        if ack.to != self.node_id {
+            return None;
+        }
+        self.names.insert(ack.sender, ack.callsign.clone());
+        Some(format!("[message {} delivered to {}]", ack.message, self.name(ack.sender)))
+    }
+}
@@ -0,0 +1,202 @@
+use bincode::{serialize, Bounded};
+use packet_layer::{packet_layer, deserialize_exact, PacketSender, Prioritized, Transport, Versioned, MAX_PAYLOAD_LEN};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::io::Error as IOError;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Instant;
+
+// registered message kinds, a kind must never be reused for a different type
+pub const KIND_AUDIO: u8 = 1;
+pub const KIND_TEXT: u8 = 2;
+pub const KIND_TEXT_ACK: u8 = 3;
+pub const KIND_FLOOR: u8 = 4;
+
+// envelopes of protocol version 1 have neither channel nor sender, those nodes all talk on the
+// default channel and are not known by id
+pub const DEFAULT_CHANNEL: u16 = 1;
+const UNKNOWN_SENDER: u16 = 0;
+
+// A type that can be sent over the multiplexer, identified on the wire by KIND.
+pub trait MessageKind: Serialize + DeserializeOwned + Prioritized + Send + 'static {
+    const KIND: u8;
+}
+
+// What the packet layer actually carries: an encoded message together with its kind, the
+// channel it was sent on and the node that sent it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    kind: u8,
+    urgent: bool,
+    channel: u16,
+    sender: u16,
+    body: Vec<u8>,
+}
+
+impl Prioritized for Envelope {
+    fn is_urgent(&self) -> bool {
+        self.urgent
+    }
+}
+
+#[derive(Deserialize)]
+struct EnvelopeV1 {
+    kind: u8,
+    urgent: bool,
+    body: Vec<u8>,
+}
+
+impl Versioned for Envelope {
+    fn decode_version(version: u8, bytes: &[u8]) -> Result<Envelope, String> {
+        if version > 1 {
+            return deserialize_exact(bytes, MAX_PAYLOAD_LEN);
+        }
+        let envelope: EnvelopeV1 = deserialize_exact(bytes, MAX_PAYLOAD_LEN)?;
+        Ok(Envelope { kind: envelope.kind, urgent: envelope.urgent, channel: DEFAULT_CHANNEL, sender: UNKNOWN_SENDER, body: envelope.body })
+    }
+}
+
+type Dispatch = Box<dyn Fn(&[u8]) + Send>;
+
+// Decides which envelopes are delivered locally.
+struct Filter {
+    channel: u16,
+    allowed: Vec<u16>,  // empty allows everybody
+    blocked: Vec<u16>,
+}
+
+impl Filter {
+    fn accepts(&self, envelope: &Envelope) -> bool {
+        envelope.channel == self.channel
+            && (self.allowed.is_empty() || self.allowed.contains(&envelope.sender))
+            && !self.blocked.contains(&envelope.sender)
+    }
+}
+
+// A node some message was received from, whether it was delivered or not.
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub node: u16,
+    pub channel: u16,  // channel of the last message
+    pub last_heard: Instant,
+}
+
+// Carries all message kinds over a single packet layer, so they share one socket, one dedup
+// cache and one relay policy. Every kind has at most one subscriber, messages of kinds nobody
+// subscribed to, sent on other channels or by filtered nodes are still relayed, but dropped
+// locally.
+pub struct Mux {
+    node_id: u16,
+    sender: Arc<Mutex<PacketSender<Envelope>>>,
+    subscribers: Arc<Mutex<HashMap<u8, Dispatch>>>,
+    filter: Arc<Mutex<Filter>>,
+    peers: Arc<Mutex<HashMap<u16, Peer>>>,
+}
+
+impl Mux {
+    pub fn new(transport: Transport, ip_address: i64, node_id: u16, channel: u16) -> Result<Mux, IOError> {
+        let (sender, receiver) = packet_layer::<Envelope>(transport, ip_address)?;
+        let subscribers: Arc<Mutex<HashMap<u8, Dispatch>>> = Arc::new(Mutex::new(HashMap::new()));
+        let dispatch_subscribers = subscribers.clone();
+        let filter = Arc::new(Mutex::new(Filter { channel: channel, allowed: Vec::new(), blocked: Vec::new() }));
+        let dispatch_filter = filter.clone();
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let dispatch_peers = peers.clone();
+        thread::spawn(move || {
+            while let Ok((envelope, _, _)) = receiver.receive() {
+                if envelope.sender != node_id && envelope.sender != UNKNOWN_SENDER {
+                    dispatch_peers.lock().unwrap().insert(envelope.sender, Peer { node: envelope.sender, channel: envelope.channel, last_heard: Instant::now() });
+                }
+                if !dispatch_filter.lock().unwrap().accepts(&envelope) {
+                    trace!("dropping message of kind {} from node {} on channel {}", envelope.kind, envelope.sender, envelope.channel);
+                    continue;
+                }
+                match dispatch_subscribers.lock().unwrap().get(&envelope.kind) {
+                    Some(dispatch) => dispatch(&envelope.body),
+                    None => debug!("no subscriber for message kind {}, dropping", envelope.kind),
+                }
+            }
+            debug!("mux dispatch thread exited");
+        });
+        Ok(Mux { node_id: node_id, sender: Arc::new(Mutex::new(sender)), subscribers: subscribers, filter: filter, peers: peers })
+    }
+
+    // all nodes heard so far, ordered by node id
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.peers.lock().unwrap().values().cloned().collect();
+        peers.sort_by_key(|peer| peer.node);
+        peers
+    }
+
+    pub fn channel(&self) -> u16 {
+        self.filter.lock().unwrap().channel
+    }
+
+    // applies to messages sent and received from now on
+    pub fn set_channel(&self, channel: u16) {
+        self.filter.lock().unwrap().channel = channel;
+    }
+
+    // only messages of allowed nodes are delivered, unless allowed is empty, messages of blocked
+    // nodes never are
+    pub fn set_node_filter(&self, allowed: Vec<u16>, blocked: Vec<u16>) {
+        let mut filter = self.filter.lock().unwrap();
+        filter.allowed = allowed;
+        filter.blocked = blocked;
+    }
+
+    // fails if T is already subscribed to, since messages would silently go missing otherwise
+    pub fn subscribe<T: MessageKind>(&self) -> Result<Receiver<T>, String> {
+        let (tx, rx) = channel();
+        let dispatch = move |body: &[u8]| {
+            match deserialize_exact::<T>(body, MAX_PAYLOAD_LEN) {
+                Ok(message) => if tx.send(message).is_err() {
+                    debug!("subscriber of message kind {} is gone", T::KIND);
+                },
+                Err(e) => error!("Cannot decode message of kind {}. Error: {}", T::KIND, e),
+            }
+        };
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.contains_key(&T::KIND) {
+            return Err(format!("message kind {} is already subscribed to", T::KIND));
+        }
+        subscribers.insert(T::KIND, Box::new(dispatch));
+        Ok(rx)
+    }
+
+    pub fn sender<T: MessageKind>(&self) -> MuxSender<T> {
+        MuxSender { node_id: self.node_id, sender: self.sender.clone(), filter: self.filter.clone(), kind: PhantomData }
+    }
+}
+
+pub struct MuxSender<T> {
+    node_id: u16,
+    sender: Arc<Mutex<PacketSender<Envelope>>>,
+    filter: Arc<Mutex<Filter>>,
+    kind: PhantomData<T>,
+}
+
+impl<T> Clone for MuxSender<T> {
+    fn clone(&self) -> Self {
+        MuxSender { node_id: self.node_id, sender: self.sender.clone(), filter: self.filter.clone(), kind: PhantomData }
+    }
+}
+
+impl<T: MessageKind> MuxSender<T> {
+    pub fn send(&self, message: &T) {
+        let body = match serialize(message, Bounded(MAX_PAYLOAD_LEN)) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Cannot encode message of kind {}, dropping it. Error: {}", T::KIND, e);
+                return;
+            }
+        };
+        let channel = self.filter.lock().unwrap().channel;
+        let envelope = Envelope { kind: T::KIND, urgent: message.is_urgent(), channel: channel, sender: self.node_id, body: body };
+        self.sender.lock().unwrap().send(envelope);
+    }
+}
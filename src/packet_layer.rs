@@ -6,18 +6,151 @@ use std::marker::Send;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
 use std::thread::{JoinHandle};
-use bincode::{serialize, deserialize, Infinite};
+use bincode::{serialize, serialized_size_bounded, deserialize_from, Bounded};
 use serde::{Serialize, Deserialize};
 use serde::de::DeserializeOwned;
-use std::sync::mpsc::{TryRecvError, RecvError};
+use std::sync::mpsc::{TryRecvError, RecvError, RecvTimeoutError};
 use std::sync::Arc;
-use std::net::{UdpSocket, SocketAddr};
-use std::io::Error as IOError;
+use std::net::{UdpSocket, SocketAddr, IpAddr};
+use std::cell::Cell;
+use std::io::{Error as IOError, ErrorKind};
+use std::time::Duration;
+use std::fmt;
+use stats;
 
 static IP_ADDR_ANY : &'static str = "0.0.0.0";
 static BROADCAST_ALL : &'static str = "255.255.255.255";
+pub const DEFAULT_PORT: u16 = 1337;
 const MAX_PACKETS_STORED: usize = 200;
+// urgent payloads are kept longer, so nodes that missed them can still request them
+const MAX_URGENT_PACKETS_STORED: usize = 1000;
+// datagrams that are already queued at the socket are handled together, urgent ones first
+const MAX_BATCH_LEN: usize = 32;
+const BATCH_WAIT_MS: u64 = 1;
+// longest wait for a datagram, payloads sent by this node are cached at least this often
+const IDLE_WAIT_MS: u64 = 10;
 
+// Every datagram starts with a fixed header: MAGIC, the protocol version and the packet kind,
+// followed by the bincode encoded packet. Compatibility rules:
+// - the version is bumped whenever the encoding of an existing packet kind changes
+// - datagrams of versions between MIN_PROTOCOL_VERSION and PROTOCOL_VERSION are decoded, so
+//   newer nodes keep decoders for the versions still found in the fleet, others are rejected
+// - new packet kinds do not bump the version, unknown kinds are ignored by older nodes
+const MAGIC: [u8; 4] = *b"WTPI";
+pub const PROTOCOL_VERSION: u8 = 2;
+// version 2 added the channel and sender of mux envelopes, version 1 ones are still decoded
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+const HEADER_LEN: usize = 6;
+const KIND_ADVERTISEMENT: u8 = 1;
+const KIND_SEND_REQUEST: u8 = 2;
+const KIND_PAYLOAD: u8 = 3;
+
+// larger datagrams are truncated on receipt, so nothing larger is ever sent
+pub const MAX_DATAGRAM_LEN: usize = 4*10240;
+// upper bounds of the encoded packets (without header), decoding stops as soon as one is exceeded
+const MAX_ADVERTISEMENT_LEN: u64 = 64;
+const MAX_SEND_REQUEST_LEN: u64 = 64;
+pub const MAX_PAYLOAD_LEN: u64 = (MAX_DATAGRAM_LEN - HEADER_LEN) as u64;
+
+// Payloads that are urgent are relayed before other traffic and stay longer in the cache.
+pub trait Prioritized {
+    fn is_urgent(&self) -> bool {
+        false
+    }
+}
+
+
+// Payloads decode the encodings of older protocol versions themselves, by default the encoding
+// is the same in all supported versions.
+pub trait Versioned: DeserializeOwned {
+    fn decode_version(_version: u8, bytes: &[u8]) -> Result<Self, String> {
+        deserialize_exact(bytes, MAX_PAYLOAD_LEN)
+    }
+}
+
+// What the worker needs from a socket, implemented by UdpSocket and by in-memory transports that
+// exercise the worker without a network.
+pub trait DatagramSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), IOError>;
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, IOError>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IOError>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), IOError> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, IOError> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IOError> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+// Datagrams are taken from a channel instead of the network and sent ones are handed to another,
+// or dropped without one. Waits for datagrams like a socket with the same read timeout would.
+// Only meant for tests and benchmarks of worker_loop.
+#[doc(hidden)]
+pub struct MemorySocket {
+    incoming: Receiver<Vec<u8>>,
+    sent: Option<Sender<Vec<u8>>>,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl MemorySocket {
+    pub fn new(incoming: Receiver<Vec<u8>>) -> MemorySocket {
+        MemorySocket { incoming: incoming, sent: None, timeout: Cell::new(None) }
+    }
+
+    pub fn with_sent(mut self, sent: Sender<Vec<u8>>) -> MemorySocket {
+        self.sent = Some(sent);
+        self
+    }
+}
+
+impl DatagramSocket for MemorySocket {
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), IOError> {
+        let closed = || IOError::new(ErrorKind::BrokenPipe, "closed");
+        let datagram = match self.timeout.get() {
+            None => self.incoming.recv().map_err(|_| closed()),
+            Some(timeout) => self.incoming.recv_timeout(timeout).map_err(|err| match err {
+                RecvTimeoutError::Timeout => IOError::new(ErrorKind::WouldBlock, "empty"),
+                RecvTimeoutError::Disconnected => closed(),
+            }),
+        }?;
+        buf[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), SocketAddr::new(IpAddr::from([127, 0, 0, 1]), DEFAULT_PORT)))
+    }
+
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> Result<usize, IOError> {
+        if let Some(ref sent) = self.sent {
+            sent.send(buf.to_vec()).map_err(|_| IOError::new(ErrorKind::BrokenPipe, "closed"))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IOError> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+// The socket the packet layer listens on and the address advertisements are broadcast to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Transport {
+    pub port: u16,
+    pub bind_addr: IpAddr,
+    pub broadcast_addr: IpAddr,
+}
+
+impl Transport {
+    pub fn new(port: u16) -> Transport {
+        Transport { port: port, bind_addr: IP_ADDR_ANY.parse().unwrap(), broadcast_addr: BROADCAST_ALL.parse().unwrap() }
+    }
+}
 
 #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Hash)]
 pub struct PacketId {
@@ -38,13 +171,15 @@ impl PacketId {
 pub struct AdvertisementPacket {
     packet: PacketId,
     advertiser: i64,
+    urgent: bool,
 }
 
 impl AdvertisementPacket {
-    pub fn new<P>(packet: &PayloadPacket<P>, advertiser: i64) -> Self {
+    pub fn new<P: Prioritized>(packet: &PayloadPacket<P>, advertiser: i64) -> Self {
         AdvertisementPacket {
             packet: packet.packet.clone(),
             advertiser: advertiser,
+            urgent: packet.payload.is_urgent(),
         }
     }
 }
@@ -52,12 +187,14 @@ impl AdvertisementPacket {
 #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Hash)]
 pub struct SendRequestPacket {
     packet: PacketId,
+    urgent: bool,
 }
 
 impl SendRequestPacket {
     pub fn new(packet: &AdvertisementPacket) -> Self {
         SendRequestPacket {
             packet: packet.packet.clone(),
+            urgent: packet.urgent,
         }
     }
 }
@@ -78,15 +215,130 @@ impl<P> PayloadPacket<P> {
 }
 
 #[derive(Deserialize, Serialize, PartialEq)]
-enum SendablePackets<P> {
+pub enum SendablePackets<P> {
     AdvertisementPacket(AdvertisementPacket),
     SendRequestPacket(SendRequestPacket),
     PayloadPacket(PayloadPacket<P>),
 }
 
+impl<P: Prioritized> SendablePackets<P> {
+    fn is_urgent(&self) -> bool {
+        match *self {
+            SendablePackets::AdvertisementPacket(ref adv) => adv.urgent,
+            SendablePackets::SendRequestPacket(ref srp) => srp.urgent,
+            SendablePackets::PayloadPacket(ref pp) => pp.payload.is_urgent(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Foreign,                  // not a walkie talkie datagram, e.g. some other service on the port
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Foreign => write!(f, "no walkie talkie header"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {} (supported {} to {})", version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            DecodeError::UnknownKind(kind) => write!(f, "unknown packet kind {}", kind),
+            DecodeError::Malformed(ref e) => write!(f, "malformed packet: {}", e),
+        }
+    }
+}
+
+// Decodes a value that has to take up exactly the given bytes, at most limit of them are read.
+pub fn deserialize_exact<T: DeserializeOwned>(bytes: &[u8], limit: u64) -> Result<T, String> {
+    let mut reader = bytes;
+    let value = deserialize_from(&mut reader, Bounded(limit)).map_err(|e| e.to_string())?;
+    if !reader.is_empty() {
+        return Err(format!("{} bytes of trailing garbage", reader.len()));
+    }
+    Ok(value)
+}
+
+pub fn encode<P: Serialize>(packet: &SendablePackets<P>) -> Result<Vec<u8>, String> {
+    let (kind, body) = match *packet {
+        SendablePackets::AdvertisementPacket(ref adv) => (KIND_ADVERTISEMENT, serialize(adv, Bounded(MAX_ADVERTISEMENT_LEN))),
+        SendablePackets::SendRequestPacket(ref srp) => (KIND_SEND_REQUEST, serialize(srp, Bounded(MAX_SEND_REQUEST_LEN))),
+        SendablePackets::PayloadPacket(ref pp) => (KIND_PAYLOAD, serialize(pp, Bounded(MAX_PAYLOAD_LEN))),
+    };
+    let body = body.map_err(|e| format!("cannot encode packet of kind {}: {}", kind, e))?;
+    let mut encoded = Vec::with_capacity(HEADER_LEN + body.len());
+    encoded.extend_from_slice(&MAGIC);
+    encoded.push(PROTOCOL_VERSION);
+    encoded.push(kind);
+    encoded.extend(body);
+    Ok(encoded)
+}
+
+// the packet id is the same in all versions, only the payload is decoded depending on it
+fn decode_payload<P: Versioned>(version: u8, body: &[u8]) -> Result<PayloadPacket<P>, String> {
+    let mut reader = body;
+    let packet = deserialize_from(&mut reader, Bounded(MAX_PAYLOAD_LEN)).map_err(|e| e.to_string())?;
+    let payload = P::decode_version(version, reader)?;
+    Ok(PayloadPacket { packet: packet, payload: payload })
+}
+
+pub fn decode<P: Versioned>(datagram: &[u8]) -> Result<SendablePackets<P>, DecodeError> {
+    if datagram.len() < HEADER_LEN || datagram[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::Foreign);
+    }
+    let version = datagram[MAGIC.len()];
+    if version < MIN_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let body = &datagram[HEADER_LEN..];
+    match datagram[MAGIC.len() + 1] {
+        KIND_ADVERTISEMENT => deserialize_exact(body, MAX_ADVERTISEMENT_LEN).map(SendablePackets::AdvertisementPacket).map_err(DecodeError::Malformed),
+        KIND_SEND_REQUEST => deserialize_exact(body, MAX_SEND_REQUEST_LEN).map(SendablePackets::SendRequestPacket).map_err(DecodeError::Malformed),
+        KIND_PAYLOAD => decode_payload(version, body).map(SendablePackets::PayloadPacket).map_err(DecodeError::Malformed),
+        kind => Err(DecodeError::UnknownKind(kind)),
+    }
+}
+
+// Payloads that can be requested by other nodes, the oldest ones are dropped first.
+struct PayloadCache<P> {
+    payloads: HashMap<PacketId, PayloadPacket<P>>,
+    age: VecDeque<PacketId>,
+    urgent_age: VecDeque<PacketId>,
+}
+
+impl<P: Prioritized> PayloadCache<P> {
+    fn new() -> Self {
+        PayloadCache {
+            payloads: HashMap::new(),
+            age: VecDeque::with_capacity(MAX_PACKETS_STORED),
+            urgent_age: VecDeque::with_capacity(MAX_URGENT_PACKETS_STORED),
+        }
+    }
+
+    fn get(&self, id: &PacketId) -> Option<&PayloadPacket<P>> {
+        self.payloads.get(id)
+    }
+
+    fn insert(&mut self, packet: PayloadPacket<P>) {
+        let (age, max_stored) = if packet.payload.is_urgent() {
+            (&mut self.urgent_age, MAX_URGENT_PACKETS_STORED)
+        } else {
+            (&mut self.age, MAX_PACKETS_STORED)
+        };
+        let id = packet.packet.clone();
+        if self.payloads.insert(id.clone(), packet).is_none() {
+            age.push_back(id);
+            if age.len() > max_stored {
+                self.payloads.remove(&age.pop_front().unwrap());
+            }
+        }
+    }
+}
+
 pub struct PacketSender<P> {
     ip_address: i64,
-    port: u16,
+    broadcast: SocketAddr,
     sequence_number: u8,
     sender: Sender<PayloadPacket<P>>,
     socket: UdpSocket,
@@ -94,18 +346,33 @@ pub struct PacketSender<P> {
 }
 
 impl<P> PacketSender<P> 
-	where P: Serialize {
+	where P: Serialize + Prioritized {
     pub fn send(&mut self, payload: P) {
         debug!("got new payload to send");
         let packet = PayloadPacket::new(payload, self.ip_address, self.sequence_number);
+        if serialized_size_bounded(&packet, MAX_PAYLOAD_LEN).is_none() {
+            error!("Payload exceeds {} Bytes, dropping it", MAX_PAYLOAD_LEN);
+            return;
+        }
         let advertisement : SendablePackets<P> = SendablePackets::AdvertisementPacket(AdvertisementPacket::new(&packet, self.ip_address));
-        self.sender.send(packet);
+        // without the worker, the payload could not be requested by anybody
+        if self.sender.send(packet).is_err() {
+            error!("packet layer worker is gone, dropping payload");
+            stats::SEND_ERRORS.inc();
+            return;
+        }
 
-        let advertisement_encoded = &serialize(&advertisement, Infinite).unwrap();
+        let advertisement_encoded = &encode(&advertisement).unwrap();
         debug!("sending {} Bytes", advertisement_encoded.len());
-        match self.socket.send_to(&advertisement_encoded, (BROADCAST_ALL, self.port)) {
-            Ok(_) => debug!("Successfully sent advertisement!"),
-            Err(e) => error!("Failed to send advertisement: {}", e)
+        match self.socket.send_to(&advertisement_encoded, self.broadcast) {
+            Ok(_) => {
+                debug!("Successfully sent advertisement!");
+                stats::ADVERTISEMENTS_SENT.inc();
+            },
+            Err(e) => {
+                error!("Failed to send advertisement: {}", e);
+                stats::SEND_ERRORS.inc();
+            }
         }
         self.sequence_number = self.sequence_number.wrapping_add(1);
         debug!("incremented sequence_number to {}", self.sequence_number);
@@ -129,23 +396,24 @@ impl<P> PacketReceiver<P> {
     }
 }
 
-pub fn packet_layer<P: 'static>(port: u16, ip_address:i64) -> Result<(PacketSender<P>, PacketReceiver<P>), IOError> 
-	where for<'de> P: Send + Clone + Serialize + Deserialize<'de>{
+pub fn packet_layer<P: 'static>(transport: Transport, ip_address:i64) -> Result<(PacketSender<P>, PacketReceiver<P>), IOError> 
+	where P: Send + Clone + Serialize + Versioned + Prioritized {
 
     let (send_tx, send_rx) = channel();
     let (receive_tx, receive_rx) = channel();
 
-    let socket = (UdpSocket::bind((IP_ADDR_ANY, port)))?;
+    let socket = (UdpSocket::bind((transport.bind_addr, transport.port)))?;
+    let broadcast = SocketAddr::new(transport.broadcast_addr, transport.port);
     let worker_socket = socket.try_clone().unwrap();
     worker_socket.set_broadcast(true)?;
 
-    let workthread = thread::spawn(move|| {worker_loop(port, ip_address, worker_socket, send_rx, receive_tx)});
+    let workthread = thread::spawn(move|| {worker_loop(broadcast, ip_address, worker_socket, send_rx, receive_tx)});
     let worker = Arc::new(workthread);
     Ok((PacketSender {
         ip_address : ip_address,
         sequence_number : 0,
         socket : socket,
-        port : port,
+        broadcast : broadcast,
         worker: worker.clone(),
         sender: send_tx,
     },
@@ -155,82 +423,126 @@ pub fn packet_layer<P: 'static>(port: u16, ip_address:i64) -> Result<(PacketSend
     }))
 }
 
-fn handle_advertisement<P>(advertisementpacket: AdvertisementPacket, socket: &UdpSocket, id_to_packet: &HashMap<PacketId, PayloadPacket<P>>, source: SocketAddr) 
-	where P:Serialize {
+fn handle_advertisement<P, S>(advertisementpacket: AdvertisementPacket, socket: &S, id_to_packet: &PayloadCache<P>, source: SocketAddr) 
+	where P: Serialize + Prioritized, S: DatagramSocket {
     info!("handling advertisement packet");
     if let None =  id_to_packet.get(&advertisementpacket.packet) {
         debug!("Haven't received Payload Packet yet, sending send request");
         let sendrequest : SendablePackets<P> = SendablePackets::SendRequestPacket(SendRequestPacket::new(&advertisementpacket));
-        socket.send_to(&serialize(&sendrequest, Infinite).unwrap(), source).unwrap_or_else(|err| {error!("Failed to send advertisement, got {}", err); 0});
+        match socket.send_to(&encode(&sendrequest).unwrap(), source) {
+            Ok(_) => stats::SEND_REQUESTS_SENT.inc(),
+            Err(err) => {
+                error!("Failed to send send request, got {}", err);
+                stats::SEND_ERRORS.inc();
+            }
+        }
     } else {
         debug!("Already got advertised Packet, ignoring advertisement.");
+        stats::DUPLICATES_IGNORED.inc();
     }
 }
 
-fn handle_send_request<P>(sendrequestpacket: SendRequestPacket, socket: &UdpSocket, id_to_packet: &HashMap<PacketId, PayloadPacket<P>>, source: SocketAddr) 
-	where P: Clone + Serialize{
+fn handle_send_request<P, S>(sendrequestpacket: SendRequestPacket, socket: &S, id_to_packet: &PayloadCache<P>, source: SocketAddr) 
+	where P: Clone + Serialize + Prioritized, S: DatagramSocket {
     info!("handling send request packet");
     if let Some(packet) = id_to_packet.get(&sendrequestpacket.packet) {
-        socket.send_to(&serialize(&SendablePackets::PayloadPacket(packet.clone()), Infinite).unwrap(), source).unwrap_or_else(|err| {error!("Failed to send send request, got {}", err); 0});
+        // cached payloads were checked against MAX_PAYLOAD_LEN when they were sent or received
+        match socket.send_to(&encode(&SendablePackets::PayloadPacket(packet.clone())).unwrap(), source) {
+            Ok(_) => stats::PAYLOADS_RELAYED.inc(),
+            Err(err) => {
+                error!("Failed to send payload, got {}", err);
+                stats::SEND_ERRORS.inc();
+            }
+        }
     } else {
         debug!("failed to find requested packet, ignoring");
+        stats::UNKNOWN_REQUESTS.inc();
     }
 }
 
-fn handle_payload<P>(payloadpacket: PayloadPacket<P>, socket: &UdpSocket, id_to_packet: &mut HashMap<PacketId, PayloadPacket<P>>, id_age: &mut VecDeque<PacketId>, ip_address: i64, port: u16, received: &Sender<PayloadPacket<P>>) 
-	where P: Clone + Serialize {
+fn handle_payload<P, S>(payloadpacket: PayloadPacket<P>, socket: &S, id_to_packet: &mut PayloadCache<P>, ip_address: i64, broadcast: SocketAddr, received: &Sender<PayloadPacket<P>>) 
+	where P: Clone + Serialize + Prioritized, S: DatagramSocket {
     info!("handling payload packet");
     if let None = id_to_packet.get(&payloadpacket.packet) {
         debug!("haven't gotten payload packet, saving");
         
-        id_to_packet.insert(payloadpacket.packet.clone(), payloadpacket.clone());
-		id_age.push_back(payloadpacket.packet.clone());
-		if id_age.len() > MAX_PACKETS_STORED {
-			id_to_packet.remove(&id_age.pop_front().unwrap());
-		}
+        id_to_packet.insert(payloadpacket.clone());
 
         let advertisement : SendablePackets<P> = SendablePackets::AdvertisementPacket(AdvertisementPacket::new(&payloadpacket, ip_address));
-        let advertisement_encoded = &serialize(&advertisement, Infinite).unwrap();
+        let advertisement_encoded = &encode(&advertisement).unwrap();
         info!("sending {} Bytes : {:?}", advertisement_encoded.len(), advertisement_encoded);
-        socket.send_to(advertisement_encoded, (BROADCAST_ALL, port)).unwrap_or_else(|err| {error!("Failed to send payload, got {}", err); 0});
+        match socket.send_to(advertisement_encoded, broadcast) {
+            Ok(_) => stats::ADVERTISEMENTS_SENT.inc(),
+            Err(err) => {
+                error!("Failed to send advertisement, got {}", err);
+                stats::SEND_ERRORS.inc();
+            }
+        }
 
+        stats::PAYLOADS_RECEIVED.inc();
         if let Err(_) = received.send(payloadpacket.clone()) {
             warn!("Cannot forward received PayloadPacket");
         }
     } else {
         debug!("already received payload packet, ignoring");
+        stats::DUPLICATES_IGNORED.inc();
     }
 }
 
-fn worker_loop<P>(port: u16, ip_address: i64, socket: UdpSocket, rx: Receiver<PayloadPacket<P>>, tx: Sender<PayloadPacket<P>>) 
-	where P: Clone + DeserializeOwned + Serialize{
+// Handles the datagrams received on socket. Payloads sent by this node arrive on rx and are cached
+// so they can be requested, new payloads from others are delivered to tx. Returns once rx is
+// disconnected.
+pub fn worker_loop<P, S>(broadcast: SocketAddr, ip_address: i64, socket: S, rx: Receiver<PayloadPacket<P>>, tx: Sender<PayloadPacket<P>>) 
+	where P: Clone + Versioned + Serialize + Prioritized, S: DatagramSocket {
     info!("worker started!");
-    let mut buffer = [0; 4*10240];
+    let mut buffer = [0; MAX_DATAGRAM_LEN];
     let mut running = true;
-    let mut id_to_payload = HashMap::new();
-    let mut id_age = VecDeque::with_capacity(MAX_PACKETS_STORED);
+    let mut id_to_payload = PayloadCache::new();
+    let mut batch = Vec::with_capacity(MAX_BATCH_LEN);
 
     while running {
-        if let Ok((amount, source)) = socket.recv_from(&mut buffer) {
-            debug!("Received a message from the socket (length: {})", amount);
-            //empty message queue
-            
-            while let Ok(pending_message) = rx.try_recv() {
-                debug!("Got a pending payload package from the channel");
-                let packet_id = pending_message.packet.clone();
-                id_to_payload.insert(packet_id, pending_message);
+        // wait for the first datagram, but not longer than payloads sent by this node may stay
+        // uncached, then collect the ones that arrived in the meantime
+        socket.set_read_timeout(Some(Duration::from_millis(IDLE_WAIT_MS))).unwrap_or_else(|err| error!("Failed to set read timeout, got {}", err));
+        let mut first = true;
+        while batch.len() < MAX_BATCH_LEN {
+            match socket.recv_from(&mut buffer) {
+                Ok((amount, source)) => {
+                    debug!("Received a message from the socket (length: {})", amount);
+                    match decode(&buffer[..amount]) {
+                        Err(DecodeError::Foreign) => trace!("ignoring foreign datagram from {}", source),
+                        Err(e @ DecodeError::UnknownKind(_)) => debug!("ignoring packet from {}: {}", source, e),
+                        Err(e) => {
+                            error!("Cannot decode recieved Packet from {}. Error: {}", source, e);
+                            stats::DECODE_ERRORS.inc();
+                        },
+                        Ok(packet) => batch.push((packet, source)),
+                    }
+                },
+                Err(_) => break,
             }
-            if let Err(TryRecvError::Disconnected) = rx.try_recv(){
-            	running = false;
+            if first {
+                socket.set_read_timeout(Some(Duration::from_millis(BATCH_WAIT_MS))).unwrap_or_else(|err| error!("Failed to set read timeout, got {}", err));
+                first = false;
             }
+        }
+
+        //empty message queue
+        while let Ok(pending_message) = rx.try_recv() {
+            debug!("Got a pending payload package from the channel");
+            id_to_payload.insert(pending_message);
+        }
+        if let Err(TryRecvError::Disconnected) = rx.try_recv(){
+        	running = false;
+        }
 
-            match deserialize(&buffer) {
-                Err(e) => error!("Cannot decode recieved Packet. Error: {}", e),
-                Ok(packet) => match packet {
-                    SendablePackets::AdvertisementPacket(adv) => handle_advertisement(adv, &socket, &id_to_payload, source),
-                    SendablePackets::SendRequestPacket(srp) => handle_send_request(srp, &socket, &id_to_payload, source),
-                    SendablePackets::PayloadPacket(pp) => handle_payload(pp, &socket, &mut id_to_payload, &mut id_age, ip_address, port, &tx),
-                }
+        // the sort is stable, so packets of the same urgency keep their order
+        batch.sort_by_key(|(packet, _): &(SendablePackets<P>, SocketAddr)| !packet.is_urgent());
+        for (packet, source) in batch.drain(..) {
+            match packet {
+                SendablePackets::AdvertisementPacket(adv) => handle_advertisement(adv, &socket, &id_to_payload, source),
+                SendablePackets::SendRequestPacket(srp) => handle_send_request(srp, &socket, &id_to_payload, source),
+                SendablePackets::PayloadPacket(pp) => handle_payload(pp, &socket, &mut id_to_payload, ip_address, broadcast, &tx),
             }
         }
     }
@@ -0,0 +1,138 @@
+use std::f64::consts::PI;
+
+// number of sub-sample steps the filter table is computed for, coefficients in between are
+// interpolated linearly, which allows arbitrary (also non rational) conversion ratios
+const PHASES: usize = 256;
+// number of zero crossings of the sinc on each side of the center
+const ZERO_CROSSINGS: f64 = 16.0;
+
+// Streaming windowed-sinc resampler for interleaved 16 bit samples. The sinc is low passed to the
+// smaller of both nyquist frequencies, so it also band limits when converting to a lower rate.
+pub struct Resampler {
+    from_rate: u32,
+    to_rate: u32,
+    channels: usize,
+    step: f64,           // input samples per output sample
+    half_len: usize,     // number of input samples used on each side of an output sample
+    table: Vec<f32>,     // (PHASES + 1) rows of 2 * half_len coefficients
+    history: Vec<Vec<f32>>,  // per channel, unconsumed input samples
+    time: f64,           // position of the next output sample, as index into history
+}
+
+fn blackman(x: f64) -> f64 {
+    // x in [-1, 1]
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Resampler {
+        assert!(from_rate > 0 && to_rate > 0 && channels > 0);
+        // cutoff relative to the input rate, slightly below nyquist to leave room for the transition band
+        let cutoff = 0.5 * 0.95 * (to_rate as f64 / from_rate as f64).min(1.0);
+        let half_len = (ZERO_CROSSINGS / (2.0 * cutoff)).ceil() as usize;
+        let taps = 2 * half_len;
+        let mut table = vec![0.0; (PHASES + 1) * taps];
+        for phase in 0..PHASES + 1 {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            for tap in 0..taps {
+                // distance of input sample (floor(time) - half_len + 1 + tap) from time
+                let x = tap as f64 + 1.0 - half_len as f64 - frac;
+                let sinc = if x == 0.0 { 2.0 * cutoff } else { (2.0 * PI * cutoff * x).sin() / (PI * x) };
+                let coefficient = sinc * blackman(x / (half_len as f64 + 1.0));
+                table[phase * taps + tap] = coefficient as f32;
+                sum += coefficient;
+            }
+            // normalize every phase to unity gain at DC
+            for tap in 0..taps {
+                table[phase * taps + tap] /= sum as f32;
+            }
+        }
+        let mut resampler = Resampler {
+            from_rate: from_rate,
+            to_rate: to_rate,
+            channels: channels,
+            step: from_rate as f64 / to_rate as f64,
+            half_len: half_len,
+            table: table,
+            history: Vec::new(),
+            time: 0.0,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    pub fn from_rate(&self) -> u32 {
+        self.from_rate
+    }
+
+    pub fn to_rate(&self) -> u32 {
+        self.to_rate
+    }
+
+    // forgets all buffered input, e.g. after a gap in the stream
+    pub fn reset(&mut self) {
+        // pad with silence, so that the first output sample is aligned with the first input sample
+        self.history = vec![vec![0.0; self.half_len - 1]; self.channels];
+        self.time = (self.half_len - 1) as f64;
+    }
+
+    // converts interleaved samples, the last half_len input samples are held back until more input arrives
+    pub fn process(&mut self, data: &[i16]) -> Result<Vec<i16>, String> {
+        if !data.len().is_multiple_of(self.channels) {
+            return Err(format!("input of {} samples is not a multiple of {} channels", data.len(), self.channels));
+        }
+        if self.from_rate == self.to_rate {
+            return Ok(data.to_vec());
+        }
+        Ok(self.convert(data))
+    }
+
+    // returns the output held back for the end of the input, as if silence followed, and resets
+    pub fn flush(&mut self) -> Vec<i16> {
+        if self.from_rate == self.to_rate {
+            return Vec::new();
+        }
+        let silence = vec![0_i16; self.half_len * self.channels];
+        let result = self.convert(&silence);
+        self.reset();
+        result
+    }
+
+    // data has to consist of whole frames
+    fn convert(&mut self, data: &[i16]) -> Vec<i16> {
+        for (pos, val) in data.iter().enumerate() {
+            self.history[pos % self.channels].push(*val as f32);
+        }
+        let taps = 2 * self.half_len;
+        let available = self.history[0].len();
+        let mut result = Vec::with_capacity((data.len() as f64 / self.step) as usize + self.channels);
+        while (self.time as usize) + self.half_len < available {
+            let index = self.time as usize;
+            let phase_pos = (self.time - index as f64) * PHASES as f64;
+            let phase = phase_pos as usize;
+            let weight = (phase_pos - phase as f64) as f32;
+            let low = &self.table[phase * taps..(phase + 1) * taps];
+            let high = &self.table[(phase + 1) * taps..(phase + 2) * taps];
+            let start = index + 1 - self.half_len;
+            for channel in 0..self.channels {
+                let input = &self.history[channel][start..start + taps];
+                let mut sum = 0.0;
+                for tap in 0..taps {
+                    sum += input[tap] * (low[tap] + weight * (high[tap] - low[tap]));
+                }
+                result.push(sum.max(i16::min_value() as f32).min(i16::max_value() as f32) as i16);
+            }
+            self.time += self.step;
+        }
+        // drop input that is not needed for future output samples any more
+        let consumed = std::cmp::min((self.time as usize + 1).saturating_sub(self.half_len), available);
+        if consumed > 0 {
+            for channel in 0..self.channels {
+                self.history[channel].drain(..consumed);
+            }
+            self.time -= consumed as f64;
+        }
+        result
+    }
+}
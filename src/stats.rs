@@ -0,0 +1,148 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::io::Error as IOError;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// longest request (line and headers) read by the metrics endpoint
+const MAX_REQUEST_LEN: u64 = 8192;
+
+// A process wide event counter. Counters only ever grow, so they can be read at any time without
+// locking and rates are computed by whoever reads them.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter { name: name, help: help, value: AtomicU64::new(0) }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn help(&self) -> &'static str {
+        self.help
+    }
+}
+
+// packet layer
+pub static ADVERTISEMENTS_SENT: Counter = Counter::new("advertisements_sent", "Advertisements broadcast for own and relayed payloads");
+pub static SEND_REQUESTS_SENT: Counter = Counter::new("send_requests_sent", "Send requests issued for advertised payloads not received yet");
+pub static PAYLOADS_RELAYED: Counter = Counter::new("payloads_relayed", "Payloads sent in answer to send requests");
+pub static PAYLOADS_RECEIVED: Counter = Counter::new("payloads_received", "New payloads received and delivered");
+pub static DUPLICATES_IGNORED: Counter = Counter::new("duplicates_ignored", "Advertisements and payloads ignored because the payload was known already");
+pub static UNKNOWN_REQUESTS: Counter = Counter::new("unknown_requests", "Send requests for payloads that are not cached (anymore)");
+pub static DECODE_ERRORS: Counter = Counter::new("decode_errors", "Datagrams of this protocol that could not be decoded");
+pub static SEND_ERRORS: Counter = Counter::new("send_errors", "Datagrams the socket refused to send");
+// audio pipeline
+pub static RING_OVERRUNS: Counter = Counter::new("ring_overruns", "Ring buffer writes that overwrote samples before they were played");
+pub static RING_UNDERRUNS: Counter = Counter::new("ring_underruns", "Ring buffer reads that found fewer samples than needed");
+pub static LATE_DATA: Counter = Counter::new("late_data", "Buckets dropped because their position was played already");
+pub static PLAY_QUEUE_DROPS: Counter = Counter::new("play_queue_drops", "Received buckets dropped because the player thread did not keep up");
+pub static XRUNS: Counter = Counter::new("xruns", "Playback buffer underruns of the audio device");
+pub static CAPTURE_OVERRUNS: Counter = Counter::new("capture_overruns", "Capture buffer overruns of the audio device");
+pub static DEVICE_REOPENS: Counter = Counter::new("device_reopens", "Audio devices opened again after they were lost");
+
+static COUNTERS: [&'static Counter; 15] = [
+    &ADVERTISEMENTS_SENT, &SEND_REQUESTS_SENT, &PAYLOADS_RELAYED, &PAYLOADS_RECEIVED, &DUPLICATES_IGNORED,
+    &UNKNOWN_REQUESTS, &DECODE_ERRORS, &SEND_ERRORS,
+    &RING_OVERRUNS, &RING_UNDERRUNS, &LATE_DATA, &PLAY_QUEUE_DROPS, &XRUNS, &CAPTURE_OVERRUNS, &DEVICE_REOPENS,
+];
+
+pub fn counters() -> &'static [&'static Counter] {
+    &COUNTERS
+}
+
+// names and current values of all counters
+pub fn snapshot() -> Vec<(&'static str, u64)> {
+    COUNTERS.iter().map(|counter| (counter.name, counter.get())).collect()
+}
+
+// text exposition format understood by Prometheus
+pub fn prometheus_text() -> String {
+    let mut text = String::new();
+    for counter in COUNTERS.iter() {
+        let name = format!("walkie_talkie_{}_total", counter.name);
+        let _ = write!(text, "# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, counter.help, name, name, counter.get());
+    }
+    text
+}
+
+// Logs the counters that changed during the last interval.
+pub fn spawn_logger(interval_s: u64) {
+    thread::spawn(move || {
+        let mut last = snapshot();
+        loop {
+            thread::sleep(Duration::from_secs(interval_s));
+            let current = snapshot();
+            let changes: Vec<String> = current.iter().zip(last.iter())
+                .filter(|&(&(_, now), &(_, before))| now != before)
+                .map(|(&(name, now), &(_, before))| format!("{} {} (+{})", name, now, now - before))
+                .collect();
+            if !changes.is_empty() {
+                info!("stats: {}", changes.join(", "));
+            }
+            last = current;
+        }
+    });
+}
+
+// [ADDR:]PORT, without an address only local scrapers are served
+pub fn parse_metrics_address(val: &str) -> Result<SocketAddr, String> {
+    match val.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+        Err(_) => val.parse().map_err(|_| format!("expected PORT or ADDR:PORT, not '{}'", val)),
+    }
+}
+
+// Answers every HTTP request on address with the counters in Prometheus format. Meant for a
+// local scraper, so there is no routing, keep-alive or authentication.
+pub fn serve_metrics(address: SocketAddr) -> Result<(), IOError> {
+    let listener = TcpListener::bind(address)?;
+    info!("serving metrics on http://{}/metrics", address);
+    if !address.ip().is_loopback() {
+        warn!("the metrics endpoint on {} is not authenticated and can be read by everybody who reaches it", address);
+    }
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => if let Err(e) = answer(stream) {
+                    debug!("metrics request failed: {}", e);
+                },
+                Err(e) => error!("cannot accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn answer(stream: TcpStream) -> Result<(), IOError> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_LEN));
+    // the request itself does not matter, but it has to be read before the connection is closed
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+    let body = prometheus_text();
+    write!(writer, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+}
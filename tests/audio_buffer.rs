@@ -0,0 +1,155 @@
+extern crate walkie_talkie_pi;
+
+use walkie_talkie_pi::audio::{self, AudioBuffer, AudioData, Bandwidth, Burst, BurstMarker, Mixer, Priority, SampleFormat, StreamFormat, Tone, ToneGenerator};
+
+const SPARE: u64 = 8;
+const LEN: u32 = 8;
+
+fn format() -> StreamFormat {
+    StreamFormat { sample_rate: 8000, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Narrow }
+}
+
+fn buffer() -> AudioBuffer {
+    AudioBuffer::new(64, SPARE, 1000000, format())
+}
+
+fn bucket(client_id: u16, burst: u32, marker: BurstMarker, pos: u64, len: usize, val: i16) -> AudioData {
+    AudioData { client_id: client_id, pos: pos, format: format(), burst: Burst { id: burst, marker: marker }, priority: Priority::Normal, destination: None, data: vec![val; len] }
+}
+
+// a single stream is mixed at half its level
+#[test]
+fn new_burst_starts_with_a_new_ring() {
+    let mut buffer = buffer();
+    buffer.store_data(bucket(1, 1, BurstMarker::Start, 1, 16, 1000)).unwrap();
+    buffer.store_data(bucket(1, 1, BurstMarker::Continue, 17, 16, 1000)).unwrap();
+    assert_eq!(buffer.get_next(LEN), Some(vec![500; 8]));
+    assert_eq!(buffer.talkers(), vec![1]);
+    // positions start at 1 again, in the old ring they would be late and dropped
+    buffer.store_data(bucket(1, 2, BurstMarker::Start, 1, 24, 2000)).unwrap();
+    // late data of the previous burst is ignored
+    buffer.store_data(bucket(1, 1, BurstMarker::Continue, 33, 16, 1000)).unwrap();
+    assert_eq!(buffer.get_next(LEN), Some(vec![1000; 8]));
+    assert_eq!(buffer.get_next(LEN), Some(vec![1000; 8]));
+}
+
+#[test]
+fn end_marker_flushes_a_short_burst() {
+    let mut buffer = buffer();
+    // less than the spare area, so without padding it would never be played
+    buffer.store_data(bucket(1, 1, BurstMarker::Start, 1, 4, 1000)).unwrap();
+    assert_eq!(buffer.get_next(LEN), None);
+    buffer.store_data(bucket(1, 1, BurstMarker::End, 5, 4, 1000)).unwrap();
+    assert!(buffer.talkers().is_empty());
+    assert_eq!(buffer.get_next(LEN), Some(vec![500; 8]));
+    // the padding is drained and the burst is over
+    assert_eq!(buffer.get_next(LEN), None);
+    // an empty end marker pads as well
+    buffer.store_data(bucket(1, 2, BurstMarker::Start, 1, 6, 1000)).unwrap();
+    buffer.store_data(bucket(1, 2, BurstMarker::End, 7, 0, 0)).unwrap();
+    assert_eq!(buffer.get_next(LEN), Some(vec![500, 500, 500, 500, 500, 500, 0, 0]));
+}
+
+#[test]
+fn tone_is_mixed_into_the_output() {
+    let mut tones = ToneGenerator::new(format());
+    // 10 ms at 8 kHz
+    tones.queue(&[Tone { freq: 1000.0, duration_ms: 10 }]);
+    let mut data = vec![100_i16; 100];
+    tones.mix_into(&mut data);
+    assert!(!tones.is_playing());
+    let peak = data[..80].iter().map(|val| (*val - 100).abs()).max().unwrap();
+    assert!(peak > 6000, "peak {}", peak);
+    assert!(data[80..].iter().all(|val| *val == 100));
+}
+
+#[test]
+fn roger_beep_follows_the_end_of_a_transmission() {
+    let mut buffer = buffer();
+    // 16 samples at 8 kHz
+    buffer.set_roger_beep(vec![Tone { freq: 1000.0, duration_ms: 2 }]);
+    buffer.store_data(bucket(1, 1, BurstMarker::Start, 1, 4, 1000)).unwrap();
+    buffer.store_data(bucket(1, 1, BurstMarker::End, 5, 4, 1000)).unwrap();
+    assert_eq!(buffer.get_next(LEN), Some(vec![500; 8]));
+    for _ in 0..2 {
+        let beep = buffer.get_next(LEN).unwrap();
+        assert!(beep.iter().any(|val| *val != 0), "{:?}", beep);
+    }
+    assert_eq!(buffer.get_next(LEN), None);
+}
+
+#[test]
+fn busy_tone_is_played_while_muted() {
+    let mut buffer = buffer();
+    let busy_tone = vec![Tone { freq: 1000.0, duration_ms: 1 }];
+    buffer.set_busy_tone(busy_tone.clone());
+    buffer.set_user_muted(true);
+    buffer.store_data(bucket(1, 1, BurstMarker::Start, 1, 16, 1000)).unwrap();
+    assert!(buffer.check_busy());
+    // the stream is muted, only the tone is heard
+    let mut expected = vec![0; 8];
+    let mut tones = ToneGenerator::new(format());
+    tones.queue(&busy_tone);
+    tones.mix_into(&mut expected);
+    assert!(expected.iter().any(|val| *val != 0));
+    assert_eq!(buffer.get_next(LEN), Some(expected));
+}
+
+#[test]
+fn emergency_stream_is_heard_while_muted() {
+    let mut buffer = buffer();
+    buffer.set_user_muted(true);
+    buffer.set_muted(true);
+    let mut emergency = bucket(2, 1, BurstMarker::Start, 1, 24, 900);
+    emergency.priority = Priority::Emergency;
+    buffer.store_data(bucket(1, 1, BurstMarker::Start, 1, 24, 1500)).unwrap();
+    buffer.store_data(emergency).unwrap();
+    // the normal stream is muted, the emergency one is mixed as one of two
+    assert_eq!(buffer.get_next(LEN), Some(vec![300; 8]));
+    buffer.set_volume(50);
+    // emergency transmissions are not turned down either
+    assert_eq!(buffer.get_next(LEN), Some(vec![300; 8]));
+}
+
+#[test]
+fn private_call_to_another_node_is_not_played() {
+    let mut buffer = buffer();
+    buffer.set_node_id(5);
+    buffer.set_call_tone(vec![Tone { freq: 1000.0, duration_ms: 1 }]);
+    let mut call = bucket(1, 1, BurstMarker::Start, 1, 24, 1000);
+    call.destination = Some(6);
+    assert_eq!(buffer.store_data(call), Ok(None));
+    assert!(buffer.talkers().is_empty());
+    assert_eq!(buffer.get_next(LEN), None);
+    // a call to this node is announced by the call tone and played
+    let mut call = bucket(2, 1, BurstMarker::Start, 1, 24, 1000);
+    call.destination = Some(5);
+    buffer.store_data(call).unwrap();
+    assert_eq!(buffer.talkers(), vec![2]);
+    let data = buffer.get_next(LEN).unwrap();
+    assert!(data != vec![500; 8], "call tone is missing");
+    assert_eq!(buffer.get_next(LEN), Some(vec![500; 8]));
+}
+
+#[test]
+fn mixer_applies_the_controls_and_publishes_its_status() {
+    let mut buffer = buffer();
+    buffer.set_busy_tone(vec![Tone { freq: 1000.0, duration_ms: 10 }]);
+    let (mut queue, queued) = audio::play_queue();
+    let mut mixer = Mixer::new(buffer, queued, LEN);
+    let control = mixer.control();
+    assert_eq!(mixer.next_bucket(), vec![0; 8]);
+    assert!(!control.channel_busy());
+    audio::queue_for_playback(&mut queue, bucket(1, 1, BurstMarker::Start, 1, 24, 1000));
+    control.set_volume(50);
+    assert_eq!(mixer.next_bucket(), vec![250; 8]);
+    let status = control.status();
+    assert_eq!(status.talkers, vec![1]);
+    assert_eq!(status.peers[0].received, 24);
+    assert!(control.channel_busy());
+    control.set_user_muted(true);
+    assert_eq!(mixer.next_bucket(), vec![0; 8]);
+    // the busy tone is queued with the next bucket
+    assert!(control.check_busy());
+    assert!(mixer.next_bucket().iter().any(|val| *val != 0));
+}
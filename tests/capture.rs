@@ -0,0 +1,128 @@
+extern crate walkie_talkie_pi;
+
+use std::thread;
+use std::time::Duration;
+use walkie_talkie_pi::audio::{AudioData, Bandwidth, Burst, BurstMarker, Priority, SampleFormat, StreamFormat};
+use walkie_talkie_pi::capture::{BurstFramer, WireEncoder};
+
+const QUIET: [i16; 4] = [10, -10, 10, -10];
+const LOUD: [i16; 4] = [10, -5000, 10, -10];
+
+fn burst(id: u32, marker: BurstMarker) -> Option<Burst> {
+    Some(Burst { id: id, marker: marker })
+}
+
+#[test]
+fn vox_ends_a_burst_after_the_hangover() {
+    let mut framer = BurstFramer::new(7).with_vox(1000, 50);
+    assert_eq!(framer.frame(&QUIET), None);
+    assert_eq!(framer.frame(&LOUD), burst(7, BurstMarker::Start));
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(framer.frame(&LOUD), burst(7, BurstMarker::Continue));
+    // the hangover restarts with every loud bucket
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(framer.frame(&QUIET), burst(7, BurstMarker::Continue));
+    assert!(framer.is_active());
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(framer.frame(&QUIET), burst(7, BurstMarker::End));
+    assert!(!framer.is_active());
+    assert_eq!(framer.frame(&QUIET), None);
+    assert_eq!(framer.frame(&LOUD), burst(8, BurstMarker::Start));
+}
+
+#[test]
+fn push_to_talk_frames_a_burst_per_press() {
+    let mut framer = BurstFramer::new(1).with_vox(1000, 50).with_push_to_talk();
+    // voice activation is ignored with push-to-talk
+    assert_eq!(framer.frame(&LOUD), None);
+    assert!(framer.set_pressed(true));
+    assert_eq!(framer.frame(&QUIET), burst(1, BurstMarker::Start));
+    assert_eq!(framer.frame(&QUIET), burst(1, BurstMarker::Continue));
+    framer.set_pressed(false);
+    assert_eq!(framer.frame(&LOUD), burst(1, BurstMarker::End));
+    assert_eq!(framer.frame(&LOUD), None);
+    framer.set_pressed(true);
+    assert_eq!(framer.frame(&QUIET), burst(2, BurstMarker::Start));
+}
+
+#[test]
+fn without_vox_everything_is_one_burst() {
+    let mut framer = BurstFramer::new(3);
+    assert!(!framer.set_pressed(true));
+    assert_eq!(framer.frame(&QUIET), burst(3, BurstMarker::Start));
+    for _ in 0..10 {
+        assert_eq!(framer.frame(&QUIET), burst(3, BurstMarker::Continue));
+    }
+}
+
+#[test]
+fn no_burst_starts_while_muted() {
+    let mut framer = BurstFramer::new(1).with_vox(1000, 50);
+    framer.set_muted(true);
+    assert_eq!(framer.frame(&LOUD), None);
+    assert!(!framer.is_active());
+    framer.set_muted(false);
+    assert_eq!(framer.frame(&LOUD), burst(1, BurstMarker::Start));
+
+    let mut framer = BurstFramer::new(1).with_push_to_talk();
+    framer.set_pressed(true);
+    framer.set_muted(true);
+    assert_eq!(framer.frame(&QUIET), None);
+    // the button is still pressed, so the burst starts as soon as capture is unmuted
+    framer.set_muted(false);
+    assert_eq!(framer.frame(&QUIET), burst(1, BurstMarker::Start));
+}
+
+#[test]
+fn muting_ends_a_running_burst() {
+    let mut framer = BurstFramer::new(1);
+    assert_eq!(framer.frame(&QUIET), burst(1, BurstMarker::Start));
+    framer.set_muted(true);
+    assert_eq!(framer.frame(&QUIET), burst(1, BurstMarker::End));
+    assert!(!framer.is_active());
+    assert_eq!(framer.frame(&QUIET), None);
+    framer.set_muted(false);
+    assert_eq!(framer.frame(&QUIET), burst(2, BurstMarker::Start));
+}
+
+#[test]
+fn encoder_flushes_the_resampler_at_the_end_of_a_burst() {
+    const LEN: usize = 1400;
+    const BUCKETS: usize = 10;
+    let capture = StreamFormat { sample_rate: 44100, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full };
+    let mut encoder = WireEncoder::new(capture, Bandwidth::Narrow, Priority::Normal);
+    for id in 1..3 {
+        let mut next_pos = 1;
+        for i in 0..BUCKETS {
+            let marker = match i {
+                0 => BurstMarker::Start,
+                i if i == BUCKETS - 1 => BurstMarker::End,
+                _ => BurstMarker::Continue,
+            };
+            let data = AudioData { client_id: 1, pos: 0, format: capture, burst: Burst { id: id, marker: marker }, priority: Priority::Normal, destination: None, data: vec![1000; LEN] };
+            let encoded = encoder.encode(data, Burst { id: id, marker: marker }).unwrap();
+            assert_eq!(encoded.pos, next_pos);
+            assert_eq!(encoded.format.sample_rate, 8000);
+            next_pos += encoded.data.len() as u64;
+            if marker == BurstMarker::End {
+                // the end of the input is not cut off
+                assert!(encoded.data[encoded.data.len() - 8..].iter().all(|val| *val > 400), "tail {:?}", &encoded.data[encoded.data.len() - 8..]);
+            }
+        }
+        let expected = (BUCKETS * LEN) as f64 * 8000.0 / 44100.0;
+        let len = (next_pos - 1) as f64;
+        assert!((len - expected).abs() <= 2.0, "burst {}: {} samples, expected {}", id, len, expected);
+    }
+}
+
+#[test]
+fn empty_end_bucket_carries_the_marker() {
+    let capture = StreamFormat { sample_rate: 8000, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full };
+    let mut encoder = WireEncoder::new(capture, Bandwidth::Narrow, Priority::Normal);
+    let data = |len| AudioData { client_id: 1, pos: 0, format: capture, burst: Burst { id: 1, marker: BurstMarker::Continue }, priority: Priority::Normal, destination: None, data: vec![1000; len] };
+    let start = encoder.encode(data(100), Burst { id: 1, marker: BurstMarker::Start }).unwrap();
+    // what muted capture sends
+    let end = encoder.encode(data(0), Burst { id: 1, marker: BurstMarker::End }).unwrap();
+    assert_eq!(end.burst, Burst { id: 1, marker: BurstMarker::End });
+    assert_eq!(end.pos, start.pos + start.data.len() as u64);
+}
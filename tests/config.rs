@@ -0,0 +1,98 @@
+extern crate walkie_talkie_pi;
+
+use walkie_talkie_pi::audio::{Bandwidth, Priority};
+use walkie_talkie_pi::config::Config;
+
+#[test]
+fn defaults_are_valid() {
+    let config = Config::parse("").unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.network.port, 1337);
+    assert_eq!(config.node.channel, 1);
+    assert!(config.security.accept_emergency);
+}
+
+#[test]
+fn sections_override_only_the_given_keys() {
+    let config = Config::parse(r#"
+        [node]
+        callsign = "base"
+        channel = 7
+        priority = "high"
+
+        [audio]
+        sample_rate = 16000
+        bandwidth = "wide"
+
+        [network]
+        port = 4000
+        broadcast_address = "192.168.1.255"
+
+        [tones]
+        roger_beep = "off"
+
+        [security]
+        blocked_nodes = [3, 4]
+    "#).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.node.callsign, Some("base".to_string()));
+    assert_eq!(config.node.channel, 7);
+    assert_eq!(config.node.priority, Priority::High);
+    assert_eq!(config.audio.sample_rate, 16000);
+    assert_eq!(config.audio.channels, 1);
+    assert_eq!(config.audio.bandwidth, Bandwidth::Wide);
+    assert_eq!(config.network.port, 4000);
+    assert_eq!(config.network.transport().broadcast_addr, "192.168.1.255".parse::<std::net::IpAddr>().unwrap());
+    assert!(config.tones.roger_beep.is_empty());
+    assert!(!config.tones.busy_tone.is_empty());
+    assert_eq!(config.security.blocked_nodes, vec![3, 4]);
+}
+
+#[test]
+fn unknown_keys_and_bad_values_are_reported() {
+    let err = Config::parse("[audio]\nchanels = 2\n").unwrap_err();
+    assert!(err.contains("chanels"), "{}", err);
+    let err = Config::parse("[node]\npriority = \"urgent\"\n").unwrap_err();
+    assert!(err.contains("urgent"), "{}", err);
+    let err = Config::parse("[network]\nport = 70000\n").unwrap_err();
+    assert!(err.contains("port"), "{}", err);
+}
+
+#[test]
+fn inconsistent_settings_fail_validation() {
+    let err = Config::parse("[audio]\nchannels = 3\n").unwrap().validate().unwrap_err();
+    assert!(err.contains("channels"), "{}", err);
+    let err = Config::parse("[buffer]\nring_buffer_size = 10000\n").unwrap().validate().unwrap_err();
+    assert!(err.contains("ring_buffer_size"), "{}", err);
+    let err = Config::parse("[buffer]\nplayback_fill = 1000\n").unwrap().validate().unwrap_err();
+    assert!(err.contains("playback_fill"), "{}", err);
+    let err = Config::parse("[processing]\nhigh_pass = 30000.0\n").unwrap().validate().unwrap_err();
+    assert!(err.contains("high_pass"), "{}", err);
+}
+
+#[test]
+fn input_and_output_device_default_to_device() {
+    let config = Config::parse("[audio]\ndevice = \"hw:1\"\noutput_device = \"hw:2\"\n").unwrap();
+    assert_eq!(config.audio.input_device(), "hw:1");
+    assert_eq!(config.audio.output_device(), "hw:2");
+    assert!(config.audio.fallback);
+}
+
+#[test]
+fn metrics_are_served_locally_unless_an_address_is_given() {
+    assert_eq!(Config::parse("").unwrap().stats.metrics_address, None);
+    let config = Config::parse("[stats]\nmetrics_address = \"9100\"").unwrap();
+    assert_eq!(config.stats.metrics_address, Some("127.0.0.1:9100".parse().unwrap()));
+    let config = Config::parse("[stats]\nmetrics_address = \"0.0.0.0:9100\"").unwrap();
+    assert_eq!(config.stats.metrics_address, Some("0.0.0.0:9100".parse().unwrap()));
+    assert!(Config::parse("[stats]\nmetrics_address = \"localhost\"").is_err());
+}
+
+#[test]
+fn negative_or_undefined_denoise_strength_is_rejected() {
+    for value in &["-0.5", "nan"] {
+        let config = Config::parse(&format!("[processing]\ndenoise_strength = {}\n", value)).unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("denoise_strength"), "{}", err);
+    }
+}
@@ -0,0 +1,105 @@
+extern crate walkie_talkie_pi;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::process;
+use walkie_talkie_pi::control::{self, Command};
+
+// removes the socket file when the test is over, also if it fails
+struct SocketPath(String);
+
+impl Deref for SocketPath {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl Drop for SocketPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn socket_path(name: &str) -> SocketPath {
+    SocketPath(env::temp_dir().join(format!("walkie-talkie-test-{}-{}.sock", name, process::id())).to_string_lossy().into_owned())
+}
+
+#[test]
+fn commands_are_parsed() {
+    assert_eq!(Command::parse("status\n"), Ok(Command::Status));
+    assert_eq!(Command::parse("channel 12"), Ok(Command::Channel(12)));
+    assert_eq!(Command::parse(" volume  80 "), Ok(Command::Volume(80)));
+    assert_eq!(Command::parse("ptt on"), Ok(Command::PushToTalk(true)));
+    assert!(Command::parse("channel").is_err());
+    assert!(Command::parse("channel 70000").is_err());
+    assert!(Command::parse("volume 1000").is_err());
+    assert!(Command::parse("ptt maybe").is_err());
+    assert!(Command::parse("reboot").is_err());
+}
+
+#[test]
+fn replies_and_errors_reach_the_client() {
+    let path = socket_path("reply");
+    let mut channel = 1;
+    control::serve(&path, move |command| match command {
+        Command::Channel(new) => {
+            channel = new;
+            Ok(Vec::new())
+        },
+        Command::Status => Ok(vec![format!("channel: {}", channel), "muted: false".to_string()]),
+        _ => Err("not supported".to_string()),
+    }).unwrap();
+
+    assert_eq!(control::request(&path, "status"), Ok(vec!["channel: 1".to_string(), "muted: false".to_string()]));
+    assert_eq!(control::request(&path, "channel 5"), Ok(Vec::new()));
+    assert_eq!(control::request(&path, "status").unwrap()[0], "channel: 5");
+    assert_eq!(control::request(&path, "mute"), Err("not supported".to_string()));
+    assert!(control::request(&path, "bogus").unwrap_err().starts_with("unknown command"));
+}
+
+#[test]
+fn stale_socket_files_are_replaced() {
+    let path = socket_path("stale");
+    drop(std::os::unix::net::UnixListener::bind(&*path).unwrap());
+    control::serve(&path, |_| Ok(Vec::new())).unwrap();
+    assert_eq!(control::request(&path, "peers"), Ok(Vec::new()));
+    // a socket that is still served is left alone
+    assert!(control::serve(&path, |_| Ok(Vec::new())).is_err());
+}
+
+#[test]
+fn socket_is_only_accessible_by_the_owner() {
+    let path = socket_path("mode");
+    control::serve(&path, |_| Ok(Vec::new())).unwrap();
+    assert_eq!(fs::metadata(&*path).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn missing_directory_is_created_private() {
+    let dir = env::temp_dir().join(format!("walkie-talkie-test-dir-{}", process::id()));
+    let path = SocketPath(dir.join("control.sock").to_string_lossy().into_owned());
+    control::serve(&path, |_| Ok(Vec::new())).unwrap();
+    assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    assert_eq!(control::request(&path, "peers"), Ok(Vec::new()));
+    drop(path);
+    fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn overlong_lines_are_discarded() {
+    let path = socket_path("long");
+    control::serve(&path, |command| Ok(vec![format!("{:?}", command)])).unwrap();
+    let stream = UnixStream::connect(&*path).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    // the end of the line is a valid command, it must not be executed on its own
+    writeln!(writer, "{} mute", "x".repeat(300)).unwrap();
+    writeln!(writer, "status").unwrap();
+    let lines: Vec<String> = BufReader::new(stream).lines().take(3).map(|line| line.unwrap()).collect();
+    assert_eq!(lines, vec!["error: line longer than 256 bytes", "Status", "ok"]);
+}
@@ -0,0 +1,90 @@
+extern crate walkie_talkie_pi;
+
+use std::f32::consts::PI;
+use walkie_talkie_pi::denoise::{HighPassFilter, NoiseSuppressor};
+
+const RATE: u32 = 8000;
+
+fn sine(freq: f32, amplitude: f32, len: usize) -> Vec<i16> {
+    (0..len).map(|n| (amplitude * (2.0 * PI * freq * n as f32 / RATE as f32).sin()) as i16).collect()
+}
+
+fn noise(state: &mut u32, amplitude: i32, len: usize) -> Vec<i16> {
+    (0..len).map(|_| {
+        *state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (((*state >> 16) % (2 * amplitude as u32)) as i32 - amplitude) as i16
+    }).collect()
+}
+
+fn peak(data: &[i16]) -> i16 {
+    data.iter().map(|val| val.saturating_abs()).max().unwrap()
+}
+
+fn energy(data: &[i16]) -> f64 {
+    data.iter().map(|val| *val as f64 * *val as f64).sum()
+}
+
+// amplitude of the freq component, independent of its phase
+fn amplitude(data: &[i16], freq: f32) -> f32 {
+    let (mut re, mut im) = (0.0, 0.0);
+    for (n, val) in data.iter().enumerate() {
+        let angle = 2.0 * PI * freq * n as f32 / RATE as f32;
+        re += *val as f32 * angle.cos();
+        im += *val as f32 * angle.sin();
+    }
+    2.0 * (re * re + im * im).sqrt() / data.len() as f32
+}
+
+#[test]
+fn high_pass_removes_dc_and_mains_hum() {
+    let mut filter = HighPassFilter::new(100.0, RATE);
+    let mut dc = vec![10000_i16; 4000];
+    filter.process(&mut dc);
+    assert!(peak(&dc[2000..]) < 10, "{}", peak(&dc[2000..]));
+
+    let mut filter = HighPassFilter::new(100.0, RATE);
+    let mut hum = sine(50.0, 10000.0, 8000);
+    filter.process(&mut hum);
+    assert!(peak(&hum[4000..]) < 3000, "{}", peak(&hum[4000..]));
+
+    let mut filter = HighPassFilter::new(100.0, RATE);
+    let mut voice = sine(1000.0, 10000.0, 8000);
+    filter.process(&mut voice);
+    assert!(peak(&voice[4000..]) > 9500, "{}", peak(&voice[4000..]));
+}
+
+#[test]
+fn suppressor_output_is_delayed_by_one_frame() {
+    // without subtraction the frames add up to the input again
+    let mut suppressor = NoiseSuppressor::new(0.0);
+    let mut state = 1;
+    let input = noise(&mut state, 5000, 2048);
+    let mut output = input.clone();
+    for bucket in output.chunks_mut(100) {
+        suppressor.process(bucket);
+    }
+    assert!(output[..256].iter().all(|val| *val == 0));
+    // the first hop is faded in, it has no previous frame to overlap with
+    for n in 384..2048 {
+        assert!((output[n] as i32 - input[n - 256] as i32).abs() <= 2, "{}: {} != {}", n, output[n], input[n - 256]);
+    }
+}
+
+#[test]
+fn stationary_noise_is_reduced_and_a_tone_survives() {
+    let mut suppressor = NoiseSuppressor::new(2.0);
+    let mut state = 1;
+    let input = noise(&mut state, 1000, 16000);
+    let mut output = input.clone();
+    suppressor.process(&mut output);
+    // more than 6 dB less than the input one frame earlier
+    let ratio = energy(&output[8256..]) / energy(&input[8000..15744]);
+    assert!(ratio < 0.25, "{}", ratio);
+
+    let tone = sine(1000.0, 4000.0, 8000);
+    let input: Vec<i16> = noise(&mut state, 1000, 8000).iter().zip(tone.iter()).map(|(a, b)| a + b).collect();
+    let mut output = input.clone();
+    suppressor.process(&mut output);
+    assert!(amplitude(&output[4000..], 1000.0) > 3600.0, "{}", amplitude(&output[4000..], 1000.0));
+    assert!(energy(&output[4000..]) < 1.1 * energy(&tone[4000..]));
+}
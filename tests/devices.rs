@@ -0,0 +1,18 @@
+extern crate alsa;
+extern crate walkie_talkie_pi;
+
+use alsa::Error;
+use walkie_talkie_pi::devices::{classify, would_block, Fault, State};
+
+#[test]
+fn stream_errors_are_classified() {
+    assert_eq!(classify(&Error::new(None, -32)), Fault::Xrun);
+    assert_eq!(classify(&Error::new(None, -86)), Fault::Suspended);
+    // unplugged USB sound cards report ENODEV, some drivers EIO
+    assert_eq!(classify(&Error::new(None, -19)), Fault::Lost);
+    assert_eq!(classify(&Error::new(None, -5)), Fault::Lost);
+    assert_eq!(classify(&Error::new(None, -22)), Fault::Other);
+    assert!(would_block(&Error::new(None, -11)));
+    assert!(!would_block(&Error::new(None, -32)));
+    assert_eq!(State::Lost.to_string(), "lost, reopening");
+}
@@ -0,0 +1,58 @@
+extern crate walkie_talkie_pi;
+
+use std::time::Instant;
+use walkie_talkie_pi::audio::{AudioBuffer, AudioData, Bandwidth, Burst, BurstMarker, Priority, SampleFormat, StreamFormat};
+use walkie_talkie_pi::devices::State;
+use walkie_talkie_pi::display::{meter, render, Snapshot};
+
+fn format() -> StreamFormat {
+    StreamFormat { sample_rate: 8000, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Full }
+}
+
+fn bucket(pos: u64, marker: BurstMarker) -> AudioData {
+    AudioData { client_id: 7, pos: pos, format: format(), burst: Burst { id: 1, marker: marker }, priority: Priority::Normal, destination: None, data: vec![16384; 80] }
+}
+
+#[test]
+fn meters_cover_silence_to_full_scale() {
+    assert!(meter(0).starts_with("[ "));
+    assert!(meter(0).ends_with("-inf dBFS"));
+    assert!(meter(i16::max_value()).starts_with("[##############################]"));
+    assert!(meter(i16::min_value()).contains("0.0 dBFS"));
+}
+
+#[test]
+fn lost_buckets_show_up_in_the_peer_stats() {
+    let mut buffer = AudioBuffer::new(8000, 800, 500, format());
+    buffer.store_data(bucket(1, BurstMarker::Start)).unwrap();
+    buffer.store_data(bucket(81, BurstMarker::Continue)).unwrap();
+    // the bucket at 161 is lost
+    buffer.store_data(bucket(241, BurstMarker::Continue)).unwrap();
+    let stats = buffer.peer_stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].received, 240);
+    assert_eq!(stats[0].lost, 80);
+    assert_eq!(stats[0].level, 16384);
+    assert_eq!(buffer.talkers(), vec![7]);
+
+    let snapshot = Snapshot {
+        node: "base (1)".to_string(),
+        channel: 3,
+        transmitting: false,
+        talkers: vec!["node 7".to_string()],
+        input_level: 0,
+        output_level: 0,
+        muted: false,
+        volume: 100,
+        peers: vec![("node 7".to_string(), stats[0])],
+        capture: State::Running,
+        playback: State::Lost,
+    };
+    let lines = render(&snapshot, &["<node 7> hi".to_string()], Instant::now());
+    assert_eq!(lines[0], "base (1) on channel 3");
+    assert_eq!(lines[1], "talking: node 7");
+    assert!(lines.contains(&"devices: capture running, playback lost, reopening".to_string()), "{:?}", lines);
+    assert!(lines.iter().any(|line| line.starts_with("peer") && line.contains("buffered")), "{:?}", lines);
+    assert!(lines.iter().any(|line| line.starts_with("node 7") && line.contains("25.0%")), "{:?}", lines);
+    assert_eq!(lines.last().unwrap(), "<node 7> hi");
+}
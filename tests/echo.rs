@@ -0,0 +1,79 @@
+extern crate walkie_talkie_pi;
+
+use std::time::Duration;
+use walkie_talkie_pi::capture::CapturePipeline;
+use walkie_talkie_pi::echo::{Duplex, DuplexMode};
+
+const RATE: u32 = 8000;
+const BUCKET_LEN: usize = 160;
+// samples queued in the playback device after every write
+const PLAYBACK_DELAY: usize = 960;
+// from the speaker to the microphone
+const ACOUSTIC_DELAY: usize = 20;
+
+fn noise(state: &mut u32, len: usize) -> Vec<i16> {
+    (0..len).map(|_| {
+        *state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        ((*state >> 16) % 16000) as i16 - 8000
+    }).collect()
+}
+
+fn energy(data: &[i16]) -> f64 {
+    data.iter().map(|val| *val as f64 * *val as f64).sum()
+}
+
+// The device plays every written sample PLAYBACK_DELAY - BUCKET_LEN samples after the bucket was
+// written, the microphone picks it up attenuated after ACOUSTIC_DELAY.
+#[test]
+fn echo_delayed_by_the_device_buffer_is_cancelled() {
+    let mut duplex = Duplex::new(DuplexMode::Full, 100000, 500, RATE);
+    let mut pipeline = CapturePipeline::new(1, RATE).with_echo_cancellation(64);
+    let mut state = 1;
+    let mut played: Vec<i16> = Vec::new();
+    let (mut echo_energy, mut residual_energy) = (0.0, 0.0);
+    for k in 0..300 {
+        let bucket = noise(&mut state, BUCKET_LEN);
+        played.extend(bucket.iter());
+        duplex.note_played(&bucket, PLAYBACK_DELAY);
+
+        // captured during the last bucket period
+        let mut captured: Vec<i16> = (0..BUCKET_LEN).map(|i| {
+            let heard = (k * BUCKET_LEN + i) as isize - BUCKET_LEN as isize - (PLAYBACK_DELAY - BUCKET_LEN + ACOUSTIC_DELAY) as isize;
+            if heard < 0 { 0 } else { (played[heard as usize] as f32 * 0.3) as i16 }
+        }).collect();
+        // captured right after the bucket was written
+        let reference = duplex.take_reference_after(BUCKET_LEN, 0, Duration::new(0, 0));
+        if k >= 280 {
+            echo_energy += energy(&captured);
+        }
+        pipeline.process(&mut captured, &reference);
+        if k >= 280 {
+            residual_energy += energy(&captured);
+        }
+    }
+    assert!(echo_energy > 0.0);
+    assert!(residual_energy < 0.01 * echo_energy, "residual {} of echo {}", residual_energy, echo_energy);
+}
+
+#[test]
+fn reference_is_handed_out_once_the_device_played_it() {
+    let mut duplex = Duplex::new(DuplexMode::Full, 100000, 500, RATE);
+    let now = Duration::new(0, 0);
+    // the device starts playing the bucket now, so the last captured bucket heard nothing of it
+    duplex.note_played(&[1000; 100], 100);
+    assert_eq!(duplex.take_reference_after(100, 0, now), vec![0; 100]);
+    duplex.note_played(&[2000; 100], 100);
+    assert_eq!(duplex.take_reference_after(100, 0, now), vec![1000; 100]);
+    // silence that was played keeps the timeline
+    duplex.note_played(&[0; 100], 100);
+    assert_eq!(duplex.take_reference_after(100, 0, now), vec![2000; 100]);
+}
+
+#[test]
+fn reference_moves_on_while_the_device_plays() {
+    let mut duplex = Duplex::new(DuplexMode::Full, 100000, 500, RATE);
+    duplex.note_played(&[1000; 100], 200);
+    duplex.note_played(&[2000; 100], 100);
+    // 100 samples at 8 kHz later the first bucket is played and the second one is being heard
+    assert_eq!(duplex.take_reference_after(100, 0, Duration::from_millis(12) + Duration::from_micros(500)), vec![2000; 100]);
+}
@@ -0,0 +1,70 @@
+extern crate walkie_talkie_pi;
+
+use std::thread;
+use std::time::Duration;
+use walkie_talkie_pi::audio::Priority;
+use walkie_talkie_pi::floor::{FloorControl, FloorMessage};
+
+fn node(id: u16) -> FloorControl {
+    FloorControl::new(id, Priority::Normal, 2000)
+}
+
+// both nodes requested before the other's request arrived, so both took the floor first
+fn simultaneous_requests(delay: Duration) {
+    let mut first = node(2);
+    let mut second = node(1);
+    let mut observer = node(3);
+    let request_first = first.request(10);
+    thread::sleep(delay);
+    let request_second = second.request(20);
+    assert!(first.holds_floor() && second.holds_floor());
+    // the messages arrive late, the observer gets them in order of sending
+    first.handle(request_second);
+    second.handle(request_first);
+    observer.handle(request_first);
+    observer.handle(request_second);
+    // the lower node id wins on every node
+    assert!(!first.holds_floor());
+    assert!(second.holds_floor());
+    assert_eq!(first.holder(), Some(1));
+    assert_eq!(observer.holder(), Some(1));
+}
+
+#[test]
+fn simultaneous_requests_agree_on_the_holder() {
+    simultaneous_requests(Duration::from_millis(0));
+}
+
+#[test]
+fn delayed_requests_agree_on_the_holder() {
+    // longer than any local time window, the outcome must not depend on the delay
+    simultaneous_requests(Duration::from_millis(400));
+}
+
+#[test]
+fn request_after_the_grant_waits_for_the_release() {
+    let mut holder = node(2);
+    let mut waiting = node(1);
+    waiting.handle(holder.request(10));
+    // the lower node id does not take over a floor it knew to be taken
+    let request = waiting.request(20);
+    assert_eq!(request, FloorMessage::Request { node: 1, priority: Priority::Normal, burst: 20, granted: false });
+    holder.handle(request);
+    assert!(holder.holds_floor());
+    assert!(!waiting.holds_floor());
+    waiting.handle(holder.release(10).unwrap());
+    assert_eq!(waiting.holder(), None);
+    holder.handle(waiting.request(21));
+    assert_eq!(holder.holder(), Some(1));
+}
+
+#[test]
+fn emergency_takes_the_floor_over() {
+    let mut holder = node(1);
+    let mut emergency = FloorControl::new(2, Priority::Emergency, 2000);
+    emergency.handle(holder.request(10));
+    thread::sleep(Duration::from_millis(50));
+    holder.handle(emergency.request(20));
+    assert!(emergency.holds_floor());
+    assert_eq!(holder.holder(), Some(2));
+}
@@ -0,0 +1,43 @@
+extern crate walkie_talkie_pi;
+
+use std::thread;
+use walkie_talkie_pi::handoff;
+
+#[test]
+fn full_queue_hands_items_back() {
+    let (mut producer, mut consumer) = handoff::channel(2);
+    assert_eq!(consumer.pop(), None);
+    producer.push(1).unwrap();
+    producer.push(2).unwrap();
+    assert_eq!(producer.push(3), Err(3));
+    assert_eq!(consumer.pop(), Some(1));
+    producer.push(3).unwrap();
+    assert_eq!(consumer.pop(), Some(2));
+    assert_eq!(consumer.pop(), Some(3));
+    assert_eq!(consumer.pop(), None);
+}
+
+#[test]
+fn items_arrive_in_order_across_threads() {
+    let (mut producer, mut consumer) = handoff::channel(16);
+    let sender = thread::spawn(move || {
+        for i in 0..100000_u32 {
+            let mut item = vec![i];
+            while let Err(back) = producer.push(item) {
+                item = back;
+                thread::yield_now();
+            }
+        }
+    });
+    let mut expected = 0;
+    while expected < 100000 {
+        match consumer.pop() {
+            Some(item) => {
+                assert_eq!(item, vec![expected]);
+                expected += 1;
+            },
+            None => thread::yield_now(),
+        }
+    }
+    sender.join().unwrap();
+}
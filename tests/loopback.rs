@@ -0,0 +1,70 @@
+extern crate walkie_talkie_pi;
+
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use walkie_talkie_pi::message::{TextAck, TextMessage};
+use walkie_talkie_pi::mux::Mux;
+use walkie_talkie_pi::packet_layer::Transport;
+
+fn text(sender: u16, text: &str) -> TextMessage {
+    TextMessage { sender: sender, callsign: format!("node {}", sender), id: 1, destination: None, text: text.to_string() }
+}
+
+// the same setup as the net-loopback command
+#[test]
+fn messages_pass_between_loopback_addresses() {
+    let port = 41337;
+    let local = "127.0.0.1".parse().unwrap();
+    let remote = "127.0.0.2".parse().unwrap();
+    let sender = Mux::new(Transport { port: port, bind_addr: local, broadcast_addr: remote }, 1, 1, 5).unwrap();
+    let receiver = Mux::new(Transport { port: port, bind_addr: remote, broadcast_addr: local }, 2, 2, 5).unwrap();
+    let tx = sender.sender::<TextMessage>();
+    let rx = receiver.subscribe::<TextMessage>().unwrap();
+
+    tx.send(&text(1, "hello"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().text, "hello");
+    assert_eq!(receiver.peers().iter().map(|peer| peer.node).collect::<Vec<u16>>(), vec![1]);
+
+    // other channels are relayed, but not delivered
+    sender.set_channel(6);
+    tx.send(&text(1, "elsewhere"));
+    assert_eq!(rx.recv_timeout(Duration::from_millis(500)).unwrap_err(), RecvTimeoutError::Timeout);
+    receiver.set_channel(6);
+    tx.send(&text(1, "here"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().text, "here");
+}
+
+#[test]
+fn messages_are_dispatched_by_kind() {
+    let port = 41338;
+    let local = "127.0.0.1".parse().unwrap();
+    let remote = "127.0.0.2".parse().unwrap();
+    let sender = Mux::new(Transport { port: port, bind_addr: local, broadcast_addr: remote }, 1, 1, 5).unwrap();
+    let receiver = Mux::new(Transport { port: port, bind_addr: remote, broadcast_addr: local }, 2, 2, 5).unwrap();
+    let texts = receiver.subscribe::<TextMessage>().unwrap();
+    let acks = receiver.subscribe::<TextAck>().unwrap();
+    // a second subscriber would take the messages away from the first one
+    assert!(receiver.subscribe::<TextAck>().is_err());
+
+    sender.sender::<TextAck>().send(&TextAck { sender: 1, callsign: "node 1".to_string(), message: 7, to: 2 });
+    sender.sender::<TextMessage>().send(&text(1, "hello"));
+    assert_eq!(texts.recv_timeout(Duration::from_secs(5)).unwrap().text, "hello");
+    assert_eq!(acks.recv_timeout(Duration::from_secs(5)).unwrap().message, 7);
+    assert_eq!(texts.recv_timeout(Duration::from_millis(200)).unwrap_err(), RecvTimeoutError::Timeout);
+    assert_eq!(acks.recv_timeout(Duration::from_millis(200)).unwrap_err(), RecvTimeoutError::Timeout);
+}
+
+#[test]
+fn oversized_messages_are_dropped() {
+    let port = 41339;
+    let local = "127.0.0.1".parse().unwrap();
+    let remote = "127.0.0.2".parse().unwrap();
+    let sender = Mux::new(Transport { port: port, bind_addr: local, broadcast_addr: remote }, 1, 1, 5).unwrap();
+    let receiver = Mux::new(Transport { port: port, bind_addr: remote, broadcast_addr: local }, 2, 2, 5).unwrap();
+    let tx = sender.sender::<TextMessage>();
+    let rx = receiver.subscribe::<TextMessage>().unwrap();
+
+    tx.send(&text(1, &"x".repeat(100000)));
+    tx.send(&text(1, "fits"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().text, "fits");
+}
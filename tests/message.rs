@@ -0,0 +1,50 @@
+extern crate walkie_talkie_pi;
+
+use walkie_talkie_pi::message::{Chat, MAX_TEXT_LEN};
+
+#[test]
+fn line_starting_with_a_node_id_is_private() {
+    let mut chat = Chat::new(1, "alpha".to_string(), 10);
+    let message = chat.compose("  @42 meet at the gate ").unwrap().unwrap();
+    assert_eq!(message.destination, Some(42));
+    assert_eq!(message.text, "meet at the gate");
+    assert_eq!((message.sender, message.id), (1, 10));
+    let message = chat.compose("hello all").unwrap().unwrap();
+    assert_eq!(message.destination, None);
+    assert_eq!(message.id, 11);
+    assert!(chat.compose("@bravo hi").is_err());
+    // nothing is sent without text
+    assert!(chat.compose("@42").unwrap().is_none());
+    assert!(chat.compose("   ").unwrap().is_none());
+}
+
+#[test]
+fn long_lines_are_cut_off() {
+    let mut chat = Chat::new(1, "alpha".to_string(), 10);
+    let line: String = "ä".repeat(MAX_TEXT_LEN + 10);
+    let message = chat.compose(&line).unwrap().unwrap();
+    assert_eq!(message.text.chars().count(), MAX_TEXT_LEN);
+}
+
+#[test]
+fn only_private_messages_are_acknowledged() {
+    let mut sender = Chat::new(1, "alpha".to_string(), 10);
+    let mut receiver = Chat::new(2, "bravo".to_string(), 20);
+    let mut bystander = Chat::new(3, "charlie".to_string(), 30);
+
+    let message = sender.compose("hello all").unwrap().unwrap();
+    let (line, ack) = receiver.receive(&message).unwrap();
+    assert_eq!(line, "<alpha (1)> hello all");
+    assert!(ack.is_none());
+    // own messages come back through the mesh and are not shown again
+    assert!(sender.receive(&message).is_none());
+
+    let message = sender.compose("@2 over here").unwrap().unwrap();
+    assert!(bystander.receive(&message).is_none());
+    let (line, ack) = receiver.receive(&message).unwrap();
+    assert_eq!(line, "[private] <alpha (1)> over here");
+    let ack = ack.unwrap();
+    assert_eq!((ack.to, ack.message), (1, message.id));
+    assert!(bystander.receive_ack(&ack).is_none());
+    assert_eq!(sender.receive_ack(&ack), Some("[message 11 delivered to bravo (2)]".to_string()));
+}
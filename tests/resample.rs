@@ -0,0 +1,107 @@
+extern crate walkie_talkie_pi;
+
+use std::f64::consts::PI;
+use walkie_talkie_pi::resample::Resampler;
+
+fn tone(freq: f64, sample_rate: u32, len: usize, amplitude: f64) -> Vec<i16> {
+    (0..len).map(|n| (amplitude * (2.0 * PI * freq * n as f64 / sample_rate as f64).sin()) as i16).collect()
+}
+
+// amplitude of the component at freq, computed with the Goertzel algorithm
+fn amplitude(data: &[i16], freq: f64, sample_rate: u32) -> f64 {
+    let coeff = 2.0 * (2.0 * PI * freq / sample_rate as f64).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for val in data {
+        let s0 = *val as f64 + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    (s1 * s1 + s2 * s2 - coeff * s1 * s2).sqrt() * 2.0 / data.len() as f64
+}
+
+// resamples in buckets of 1400 samples, like the recorder does, and skips the settling time
+fn resample(data: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    let mut resampler = Resampler::new(from_rate, to_rate, 1);
+    let mut result = Vec::new();
+    for bucket in data.chunks(1400) {
+        result.extend(resampler.process(bucket).unwrap());
+    }
+    result.split_off(to_rate as usize / 10)
+}
+
+fn db(ratio: f64) -> f64 {
+    20.0 * ratio.log10()
+}
+
+#[test]
+fn passband_is_flat_when_downsampling() {
+    for freq in &[300.0, 1000.0, 3000.0, 6000.0] {
+        let output = resample(&tone(*freq, 44100, 44100, 10000.0), 44100, 16000);
+        let gain = db(amplitude(&output, *freq, 16000) / 10000.0);
+        assert!(gain.abs() < 0.5, "{} Hz: gain {} dB", freq, gain);
+    }
+}
+
+#[test]
+fn aliases_are_suppressed_when_downsampling() {
+    // 12 kHz is above the nyquist frequency of 16 kHz and would alias to 4 kHz
+    let output = resample(&tone(12000.0, 44100, 44100, 10000.0), 44100, 16000);
+    let gain = db(amplitude(&output, 4000.0, 16000) / 10000.0);
+    assert!(gain < -60.0, "alias at 4 kHz: {} dB", gain);
+}
+
+#[test]
+fn passband_is_flat_when_upsampling() {
+    for freq in &[300.0, 1000.0, 3000.0] {
+        let output = resample(&tone(*freq, 8000, 8000, 10000.0), 8000, 48000);
+        let gain = db(amplitude(&output, *freq, 48000) / 10000.0);
+        assert!(gain.abs() < 0.5, "{} Hz: gain {} dB", freq, gain);
+    }
+}
+
+#[test]
+fn images_are_suppressed_when_upsampling() {
+    // zero stuffing a 1 kHz tone at 8 kHz creates images at 7 kHz and 9 kHz
+    let output = resample(&tone(1000.0, 8000, 8000, 10000.0), 8000, 48000);
+    for image in &[7000.0, 9000.0] {
+        let gain = db(amplitude(&output, *image, 48000) / 10000.0);
+        assert!(gain < -60.0, "image at {} Hz: {} dB", image, gain);
+    }
+}
+
+#[test]
+fn non_integer_ratio_keeps_frequency() {
+    let output = resample(&tone(1000.0, 44100, 44100, 10000.0), 44100, 48000);
+    assert!(db(amplitude(&output, 1000.0, 48000) / 10000.0).abs() < 0.5);
+    assert!(db(amplitude(&output, 1088.4, 48000) / 10000.0) < -40.0);
+}
+
+#[test]
+fn output_length_follows_ratio() {
+    let mut resampler = Resampler::new(44100, 16000, 2);
+    let mut len = 0;
+    for _ in 0..100 {
+        len += resampler.process(&vec![0_i16; 2 * 441]).unwrap().len();
+    }
+    // 100 * 441 frames at 44.1 kHz are 1 s, minus the samples held back for the filter
+    assert!(len % 2 == 0);
+    assert!(len <= 2 * 16000 && len > 2 * 15900, "got {} samples", len);
+}
+
+#[test]
+fn same_rate_is_passed_through() {
+    let input = tone(1000.0, 16000, 1000, 10000.0);
+    let mut resampler = Resampler::new(16000, 16000, 1);
+    assert_eq!(resampler.process(&input).unwrap(), input);
+}
+
+#[test]
+fn misaligned_input_is_rejected() {
+    let mut resampler = Resampler::new(44100, 16000, 2);
+    assert!(resampler.process(&[0; 3]).is_err());
+    // the rejected input is not buffered, the following frames are still converted in step
+    let len = resampler.process(&vec![0_i16; 2 * 44100]).unwrap().len();
+    assert!(len % 2 == 0);
+    // the same rate is only passed through for whole frames, too
+    assert!(Resampler::new(16000, 16000, 2).process(&[0; 5]).is_err());
+}
@@ -0,0 +1,68 @@
+extern crate walkie_talkie_pi;
+
+use walkie_talkie_pi::audio::RingBuffer;
+
+fn samples(first: i16, len: usize) -> Vec<i16> {
+    (0..len).map(|val| first + val as i16).collect()
+}
+
+#[test]
+fn reads_and_writes_ending_at_the_buffer_end_wrap_around() {
+    let mut ring = RingBuffer::new(16, 2, 1000000);
+    let mut pos = 1;
+    let mut read = 0;
+    for _ in 0..3 {
+        ring.store_samples(pos, &samples(pos as i16, 5)).unwrap();
+        pos += 5;
+    }
+    for _ in 0..40 {
+        ring.store_samples(pos, &samples(pos as i16, 5)).unwrap();
+        pos += 5;
+        let data = ring.get_next(5).unwrap();
+        let start = ring.next_pos().unwrap() - 5;
+        assert!(start > read);
+        assert_eq!(data, samples(start as i16, 5));
+        read = start;
+    }
+}
+
+#[test]
+fn first_read_waits_for_more_than_spare() {
+    let mut ring = RingBuffer::new(16, 4, 1000000);
+    ring.store_samples(1, &samples(1, 5)).unwrap();
+    assert_eq!(ring.get_next(4), None);
+    ring.store_samples(6, &samples(6, 1)).unwrap();
+    assert_eq!(ring.get_next(4), Some(samples(1, 4)));
+}
+
+#[test]
+fn invalid_network_data_is_rejected() {
+    let mut ring = RingBuffer::new(16, 2, 1000000);
+    assert!(ring.store_samples(1, &samples(1, 16)).is_err());
+    assert!(ring.store_samples(0, &samples(1, 4)).is_err());
+    assert!(ring.store_samples(u64::max_value() - 2, &samples(1, 4)).is_err());
+    assert_eq!(ring.store_samples(5, &[]), Ok(None));
+}
+
+#[test]
+fn late_data_does_not_overwrite_unread_samples() {
+    let mut ring = RingBuffer::new(16, 2, 1000000);
+    ring.store_samples(1, &samples(1, 10)).unwrap();
+    assert_eq!(ring.get_next(4), Some(samples(1, 4)));
+    ring.store_samples(11, &samples(11, 5)).unwrap();
+    ring.store_samples(16, &samples(16, 5)).unwrap();
+    // positions 2 to 4 were read already, their slots hold positions 18 to 20 now
+    ring.store_samples(2, &samples(100, 5)).unwrap();
+    assert_eq!(ring.get_next(8), Some(vec![103, 104, 7, 8, 9, 10, 11, 12]));
+    assert_eq!(ring.get_next(7), Some(samples(13, 7)));
+}
+
+#[test]
+fn skipped_positions_are_silent() {
+    let mut ring = RingBuffer::new(16, 2, 1000000);
+    ring.store_samples(1, &samples(1, 8)).unwrap();
+    assert_eq!(ring.get_next(4), Some(samples(1, 4)));
+    ring.store_samples(30, &samples(30, 5)).unwrap();
+    assert_eq!(ring.get_next(4), Some(vec![0; 4]));
+    assert_eq!(ring.get_next(8), Some(vec![0, 0, 0, 0, 0, 0, 0, 30]));
+}
@@ -0,0 +1,41 @@
+extern crate walkie_talkie_pi;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use walkie_talkie_pi::audio::RingBuffer;
+use walkie_talkie_pi::stats;
+
+#[test]
+fn ring_buffer_overruns_and_late_data_are_counted() {
+    let overruns = stats::RING_OVERRUNS.get();
+    let late = stats::LATE_DATA.get();
+    let mut ring = RingBuffer::new(16, 2, 1000000);
+    ring.store_samples(1, &[1; 5]).unwrap();
+    ring.get_next(4).unwrap();
+    // the writer gets more than a buffer ahead of the reader
+    for pos in 0..5 {
+        ring.store_samples(6 + pos * 5, &[1; 5]).unwrap();
+    }
+    ring.store_samples(1, &[1; 5]).unwrap();
+    assert!(stats::RING_OVERRUNS.get() > overruns);
+    assert!(stats::LATE_DATA.get() > late);
+}
+
+#[test]
+fn counters_are_served_in_prometheus_format() {
+    stats::XRUNS.inc();
+    let text = stats::prometheus_text();
+    assert!(text.contains("# TYPE walkie_talkie_xruns_total counter\n"));
+    assert!(text.lines().any(|line| line.starts_with("walkie_talkie_xruns_total ") && line != "walkie_talkie_xruns_total 0"));
+    assert_eq!(stats::snapshot().len(), stats::counters().len());
+
+    // find a free port
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    stats::serve_metrics(address).unwrap();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+    assert!(response.contains("walkie_talkie_advertisements_sent_total"));
+}
@@ -0,0 +1,124 @@
+extern crate walkie_talkie_pi;
+
+use walkie_talkie_pi::audio::{AudioData, Bandwidth, Burst, BurstMarker, Priority, SampleFormat, StreamFormat};
+use walkie_talkie_pi::mux::{Envelope, DEFAULT_CHANNEL};
+use walkie_talkie_pi::packet_layer::{decode, encode, AdvertisementPacket, DecodeError, PayloadPacket, SendRequestPacket, SendablePackets, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+fn audio(len: usize) -> AudioData {
+    AudioData {
+        client_id: 7,
+        pos: 1,
+        format: StreamFormat { sample_rate: 16000, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Wide },
+        burst: Burst { id: 42, marker: BurstMarker::Start },
+        priority: Priority::Normal,
+        destination: None,
+        data: (0..len).map(|val| val as i16).collect(),
+    }
+}
+
+fn datagrams() -> Vec<Vec<u8>> {
+    let payload = PayloadPacket::new(audio(700), 1234, 5);
+    let advertisement = AdvertisementPacket::new(&payload, 1234);
+    let send_request = SendRequestPacket::new(&advertisement);
+    vec![
+        encode(&SendablePackets::AdvertisementPacket::<AudioData>(advertisement)).unwrap(),
+        encode(&SendablePackets::SendRequestPacket::<AudioData>(send_request)).unwrap(),
+        encode(&SendablePackets::PayloadPacket(payload)).unwrap(),
+    ]
+}
+
+#[test]
+fn all_kinds_survive_a_round_trip() {
+    for datagram in datagrams() {
+        let packet = decode::<AudioData>(&datagram).unwrap();
+        assert_eq!(encode(&packet).unwrap(), datagram);
+    }
+}
+
+#[test]
+fn trailing_garbage_is_rejected() {
+    for mut datagram in datagrams() {
+        datagram.push(0);
+        match decode::<AudioData>(&datagram) {
+            Err(DecodeError::Malformed(_)) => {},
+            _ => panic!("trailing byte accepted"),
+        }
+    }
+}
+
+#[test]
+fn truncated_datagrams_are_rejected() {
+    for datagram in datagrams() {
+        for len in 0..datagram.len() {
+            assert!(decode::<AudioData>(&datagram[..len]).is_err(), "prefix of {} bytes accepted", len);
+        }
+    }
+}
+
+#[test]
+fn oversized_length_prefix_is_rejected() {
+    let mut datagram = datagrams().pop().unwrap();
+    // the length of the sample vector is the last u64 before the 700 samples
+    let len_pos = datagram.len() - 2 * 700 - 8;
+    for byte in &mut datagram[len_pos..len_pos + 8] {
+        *byte = 0xff;
+    }
+    match decode::<AudioData>(&datagram) {
+        Err(DecodeError::Malformed(_)) => {},
+        _ => panic!("oversized length accepted"),
+    }
+}
+
+#[test]
+fn payloads_above_the_limit_are_not_encoded() {
+    let payload = PayloadPacket::new(audio(30000), 1234, 5);
+    assert!(encode(&SendablePackets::PayloadPacket(payload)).is_err());
+}
+
+#[test]
+fn foreign_and_unknown_datagrams_are_told_apart() {
+    let mut datagram = datagrams().pop().unwrap();
+    match decode::<AudioData>(b"hello world") {
+        Err(DecodeError::Foreign) => {},
+        _ => panic!("foreign datagram not detected"),
+    }
+    datagram[4] = PROTOCOL_VERSION + 1;
+    match decode::<AudioData>(&datagram) {
+        Err(DecodeError::UnsupportedVersion(version)) => assert_eq!(version, PROTOCOL_VERSION + 1),
+        _ => panic!("unsupported version accepted"),
+    }
+    datagram[4] = PROTOCOL_VERSION;
+    datagram[5] = 200;
+    match decode::<AudioData>(&datagram) {
+        Err(DecodeError::UnknownKind(200)) => {},
+        _ => panic!("unknown kind accepted"),
+    }
+}
+
+// payload of an envelope with kind 2 and a body of 3 bytes, as sent by version 1 nodes
+fn envelope_datagram(version: u8, channel_and_sender: &[u8]) -> Vec<u8> {
+    let mut datagram = b"WTPI".to_vec();
+    datagram.extend_from_slice(&[version, 3]);
+    // packet id: source address 1234 and sequence number 5
+    datagram.extend_from_slice(&[0xd2, 0x04, 0, 0, 0, 0, 0, 0, 5]);
+    // kind and urgency
+    datagram.extend_from_slice(&[2, 0]);
+    datagram.extend_from_slice(channel_and_sender);
+    datagram.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0, 7, 8, 9]);
+    datagram
+}
+
+#[test]
+fn version_1_envelopes_are_still_decoded() {
+    assert_eq!(MIN_PROTOCOL_VERSION, 1);
+    let packet = decode::<Envelope>(&envelope_datagram(1, &[])).unwrap();
+    // they are relayed in the current version, on the default channel and without a sender
+    let channel = [DEFAULT_CHANNEL as u8, (DEFAULT_CHANNEL >> 8) as u8, 0, 0];
+    assert_eq!(encode(&packet).unwrap(), envelope_datagram(PROTOCOL_VERSION, &channel));
+    let current = envelope_datagram(PROTOCOL_VERSION, &[6, 0, 42, 0]);
+    assert_eq!(encode(&decode::<Envelope>(&current).unwrap()).unwrap(), current);
+    match decode::<Envelope>(&envelope_datagram(MIN_PROTOCOL_VERSION - 1, &[])) {
+        Err(DecodeError::UnsupportedVersion(0)) => {},
+        _ => panic!("version 0 accepted"),
+    }
+}
@@ -0,0 +1,83 @@
+extern crate walkie_talkie_pi;
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use walkie_talkie_pi::audio::{AudioData, Bandwidth, Burst, BurstMarker, Priority, SampleFormat, StreamFormat};
+use walkie_talkie_pi::packet_layer::{self, AdvertisementPacket, MemorySocket, PayloadPacket, SendRequestPacket, SendablePackets};
+
+fn payload(priority: Priority, sequence_number: u8) -> PayloadPacket<AudioData> {
+    let format = StreamFormat { sample_rate: 16000, channels: 1, sample_format: SampleFormat::S16, bandwidth: Bandwidth::Wide };
+    let data = AudioData { client_id: 7, pos: 1, format: format, burst: Burst { id: 1, marker: BurstMarker::Start }, priority: priority, destination: None, data: vec![0; 100] };
+    PayloadPacket::new(data, 1234, sequence_number)
+}
+
+fn encode(packet: PayloadPacket<AudioData>) -> Vec<u8> {
+    packet_layer::encode(&SendablePackets::PayloadPacket(packet)).unwrap()
+}
+
+#[test]
+fn urgent_datagrams_of_a_batch_are_handled_first() {
+    let (datagrams, incoming) = channel();
+    let (sent, outgoing) = channel();
+    let (local, local_payloads) = channel::<PayloadPacket<AudioData>>();
+    let (delivered, received) = channel();
+    // everything is queued before the worker starts, so it is a single batch
+    let packets = vec![payload(Priority::Normal, 1), payload(Priority::Normal, 2), payload(Priority::Emergency, 3), payload(Priority::Normal, 4)];
+    for packet in &packets {
+        datagrams.send(encode(packet.clone())).unwrap();
+    }
+    let broadcast = "127.255.255.255:1337".parse().unwrap();
+    let worker = thread::spawn(move || {
+        let socket = MemorySocket::new(incoming).with_sent(sent);
+        packet_layer::worker_loop(broadcast, 2, socket, local_payloads, delivered);
+    });
+
+    let order: Vec<Vec<u8>> = (0..4).map(|_| encode(received.recv().unwrap())).collect();
+    let expected: Vec<Vec<u8>> = [2, 0, 1, 3].iter().map(|index| encode(packets[*index].clone())).collect();
+    assert!(order == expected, "emergency payload was not delivered first");
+    // the payloads are advertised in the same order
+    for index in &[2, 0, 1, 3] {
+        let advertisement = AdvertisementPacket::new(&packets[*index], 2);
+        assert_eq!(outgoing.recv().unwrap(), packet_layer::encode(&SendablePackets::AdvertisementPacket::<AudioData>(advertisement)).unwrap());
+    }
+
+    drop(local);
+    drop(datagrams);
+    worker.join().unwrap();
+}
+
+fn spawn_worker(incoming: Receiver<Vec<u8>>, sent: Sender<Vec<u8>>, local_payloads: Receiver<PayloadPacket<AudioData>>) -> thread::JoinHandle<()> {
+    let (delivered, _received) = channel();
+    let broadcast = "127.255.255.255:1337".parse().unwrap();
+    thread::spawn(move || {
+        let socket = MemorySocket::new(incoming).with_sent(sent);
+        packet_layer::worker_loop(broadcast, 2, socket, local_payloads, delivered);
+    })
+}
+
+#[test]
+fn own_payloads_can_be_requested() {
+    let (datagrams, incoming) = channel();
+    let (sent, outgoing) = channel();
+    let (local, local_payloads) = channel();
+    let worker = spawn_worker(incoming, sent, local_payloads);
+    let packet = payload(Priority::Normal, 1);
+    local.send(packet.clone()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let request = SendRequestPacket::new(&AdvertisementPacket::new(&packet, 1234));
+    datagrams.send(packet_layer::encode(&SendablePackets::SendRequestPacket::<AudioData>(request)).unwrap()).unwrap();
+    assert_eq!(outgoing.recv_timeout(Duration::from_secs(5)).unwrap(), encode(packet));
+    drop(local);
+    worker.join().unwrap();
+}
+
+#[test]
+fn worker_ends_without_traffic_once_the_sender_is_gone() {
+    let (_datagrams, incoming) = channel();
+    let (sent, _outgoing) = channel();
+    let (local, local_payloads) = channel::<PayloadPacket<AudioData>>();
+    let worker = spawn_worker(incoming, sent, local_payloads);
+    drop(local);
+    worker.join().unwrap();
+}